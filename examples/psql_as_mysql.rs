@@ -131,6 +131,7 @@ impl<W: io::Write> MysqlShim<W> for Postgres {
                             column: t.name().to_owned(),
                             coltype: ct,
                             colflags: ColumnFlags::empty(),
+                            ..Default::default()
                         }
                     })
                     .collect();
@@ -145,6 +146,7 @@ impl<W: io::Write> MysqlShim<W> for Postgres {
                             column: c.name().to_owned(),
                             coltype: ct,
                             colflags: ColumnFlags::empty(),
+                            ..Default::default()
                         }
                     })
                     .collect();
@@ -262,6 +264,7 @@ fn answer_rows<W: io::Write>(
                         column: c.name().to_owned(),
                         coltype: ct,
                         colflags: ColumnFlags::empty(),
+                        ..Default::default()
                     }
                 })
                 .collect();
@@ -0,0 +1,533 @@
+use crate::{writers, ErrorKind, MysqlIntermediary, MysqlShim, SCRAMBLE_LENGTH};
+use async_trait::async_trait;
+use rsa::pkcs8::{EncodePublicKey, LineEnding};
+use rsa::{Oaep, RsaPrivateKey};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+
+/// The authentication methods a server can ask its clients to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AuthPlugin {
+    /// `mysql_native_password`, where the client proves knowledge of the password with a SHA1
+    /// scramble.
+    #[default]
+    MysqlNativePassword,
+    /// `caching_sha2_password`, the default for MySQL 8 clients.
+    ///
+    /// The first time a user logs in, they must send their password either over TLS or encrypted
+    /// with the server's RSA key (see
+    /// [`ServerOptions::with_rsa_key`](struct.ServerOptions.html#method.with_rsa_key)). After
+    /// that, a SHA256 scramble is enough until the server's
+    /// [`CachingSha2PasswordCache`](struct.CachingSha2PasswordCache.html) forgets the user.
+    CachingSha2Password,
+    /// `mysql_clear_password`, where the client sends its password as-is.
+    ///
+    /// This is meant for backends such as LDAP that need the password itself to check it, and
+    /// should only be used over TLS. Clients usually have to opt in to it explicitly.
+    MysqlClearPassword,
+    /// MariaDB's `client_ed25519`, where the client signs a challenge with a key derived from its
+    /// password, and the server checks the signature against the account's public key.
+    #[cfg(feature = "ed25519")]
+    ClientEd25519,
+}
+
+impl AuthPlugin {
+    /// The name of the plugin, as sent over the wire.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AuthPlugin::MysqlNativePassword => "mysql_native_password",
+            AuthPlugin::CachingSha2Password => "caching_sha2_password",
+            AuthPlugin::MysqlClearPassword => "mysql_clear_password",
+            #[cfg(feature = "ed25519")]
+            AuthPlugin::ClientEd25519 => "client_ed25519",
+        }
+    }
+
+    /// The challenge sent to clients that are asked to switch to this plugin.
+    fn challenge(self, scramble: &[u8; 32]) -> Vec<u8> {
+        match self {
+            #[cfg(feature = "ed25519")]
+            AuthPlugin::ClientEd25519 => scramble.to_vec(),
+            _ => {
+                let mut challenge = scramble[..SCRAMBLE_LENGTH].to_vec();
+                challenge.push(0);
+                challenge
+            }
+        }
+    }
+
+    /// Whether the plugin can use a response to the scramble in the initial handshake, or needs
+    /// to send a challenge of its own.
+    fn uses_initial_scramble(self) -> bool {
+        match self {
+            #[cfg(feature = "ed25519")]
+            AuthPlugin::ClientEd25519 => false,
+            _ => true,
+        }
+    }
+}
+
+/// Implementors of this trait decide which clients are allowed to log in.
+///
+/// Which method the server calls depends on the [`AuthPlugin`](enum.AuthPlugin.html) in use for
+/// the user. If it returns `false`, the client is sent an `ER_ACCESS_DENIED_ERROR` and the
+/// connection is closed. Both methods deny everyone by default, so implementors only need to
+/// provide the ones for the plugins they use.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Check a `mysql_native_password` response from `username`.
+    ///
+    /// `scramble` is the random challenge the server sent in its initial handshake, and
+    /// `auth_response` is what the client computed from that challenge and its password.
+    async fn authenticate(&self, _username: &str, _scramble: &[u8], _auth_response: &[u8]) -> bool {
+        false
+    }
+
+    /// Check a password that `username` has sent in full.
+    ///
+    /// This is used by `mysql_clear_password`, and by `caching_sha2_password` when the user is
+    /// not in the server's cache, once the password has been received over TLS or decrypted with
+    /// the server's RSA key.
+    async fn verify_password(&self, _username: &str, _password: &[u8]) -> bool {
+        false
+    }
+
+    /// The plugin `username` has to authenticate with.
+    ///
+    /// If the client answered the initial handshake using a different plugin, it is sent an
+    /// `AuthSwitchRequest` asking it to try again with this one. `None` uses the server's
+    /// default, as set with
+    /// [`ServerOptions::with_auth_plugin`](struct.ServerOptions.html#method.with_auth_plugin).
+    async fn auth_plugin(&self, _username: &str) -> Option<AuthPlugin> {
+        None
+    }
+
+    /// The `client_ed25519` public key of `username`, or `None` if there is no such user.
+    ///
+    /// See [`ed25519_password`](fn.ed25519_password.html).
+    #[cfg(feature = "ed25519")]
+    async fn ed25519_public_key(&self, _username: &str) -> Option<[u8; 32]> {
+        None
+    }
+}
+
+/// An [`Authenticator`](trait.Authenticator.html) that verifies `mysql_native_password` responses
+/// against a set of stored password hashes.
+///
+/// Passwords are kept the same way MySQL keeps them in `mysql.user`, as `SHA1(SHA1(password))`,
+/// so hashes can be copied over from an existing server with
+/// [`add_user_hash`](struct.NativePasswordAuthenticator.html#method.add_user_hash).
+#[derive(Debug, Clone, Default)]
+pub struct NativePasswordAuthenticator {
+    // `None` for accounts without a password
+    users: HashMap<String, Option<[u8; 20]>>,
+}
+
+impl NativePasswordAuthenticator {
+    /// Create an authenticator that does not yet know of any users.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow `username` to log in with `password`. An empty password allows the user to log in
+    /// without one.
+    pub fn add_user(&mut self, username: &str, password: &[u8]) {
+        let hash = if password.is_empty() {
+            None
+        } else {
+            Some(native_password_hash(password))
+        };
+        self.users.insert(username.to_owned(), hash);
+    }
+
+    /// Allow `username` to log in with the password whose stored hash is `hash`.
+    ///
+    /// See [`native_password_hash`](fn.native_password_hash.html).
+    pub fn add_user_hash(&mut self, username: &str, hash: [u8; 20]) {
+        self.users.insert(username.to_owned(), Some(hash));
+    }
+
+    /// Forget about `username`, so that it can no longer log in.
+    pub fn remove_user(&mut self, username: &str) {
+        self.users.remove(username);
+    }
+}
+
+#[async_trait]
+impl Authenticator for NativePasswordAuthenticator {
+    async fn authenticate(&self, username: &str, scramble: &[u8], auth_response: &[u8]) -> bool {
+        match self.users.get(username) {
+            Some(Some(hash)) => verify_native_password(hash, scramble, auth_response),
+            Some(None) => auth_response.is_empty(),
+            None => false,
+        }
+    }
+
+    async fn verify_password(&self, username: &str, password: &[u8]) -> bool {
+        match self.users.get(username) {
+            Some(Some(hash)) => constant_time_eq(&native_password_hash(password), hash),
+            Some(None) => password.is_empty(),
+            None => false,
+        }
+    }
+}
+
+/// The server-side cache behind `caching_sha2_password`'s fast authentication path.
+///
+/// Once a user has logged in with their full password, `SHA256(SHA256(password))` is kept here,
+/// and later logins by the same user are checked against it without involving the
+/// [`Authenticator`](trait.Authenticator.html). Entries should be removed when a user's password
+/// changes.
+#[derive(Debug, Default)]
+pub struct CachingSha2PasswordCache {
+    entries: Mutex<HashMap<String, [u8; 32]>>,
+}
+
+impl CachingSha2PasswordCache {
+    /// Forget `username`, so that their next login goes through full authentication.
+    pub fn remove(&self, username: &str) {
+        self.entries.lock().unwrap().remove(username);
+    }
+
+    /// Forget all users.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn insert(&self, username: &str, password: &[u8]) {
+        let hash = Sha256::digest(Sha256::digest(password)).into();
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(username.to_owned(), hash);
+    }
+
+    // The client sends `SHA256(password) XOR SHA256(SHA256(SHA256(password)) + scramble)`, which
+    // is undone the same way as for `mysql_native_password`.
+    fn verify(&self, username: &str, scramble: &[u8], auth_response: &[u8]) -> bool {
+        let entries = self.entries.lock().unwrap();
+        let hash = match entries.get(username) {
+            Some(hash) => hash,
+            None => return false,
+        };
+        if auth_response.len() != 32 {
+            return false;
+        }
+
+        let mask = Sha256::new()
+            .chain_update(hash)
+            .chain_update(scramble)
+            .finalize();
+        let mut candidate = [0u8; 32];
+        for i in 0..32 {
+            candidate[i] = auth_response[i] ^ mask[i];
+        }
+        constant_time_eq(&Sha256::digest(candidate), hash)
+    }
+}
+
+/// The key pair clients use to send their password over unencrypted connections.
+pub(crate) struct RsaKey {
+    key: RsaPrivateKey,
+    public_pem: String,
+}
+
+impl RsaKey {
+    pub(crate) fn new(key: RsaPrivateKey) -> Self {
+        let public_pem = key
+            .to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .expect("RSA public keys can always be PEM-encoded");
+        RsaKey { key, public_pem }
+    }
+
+    // Clients XOR the NUL-terminated password with the scramble before encrypting it.
+    fn decrypt_password(&self, scramble: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+        let mut password = self.key.decrypt(Oaep::new::<Sha1>(), data).ok()?;
+        for (i, b) in password.iter_mut().enumerate() {
+            *b ^= scramble[i % scramble.len()];
+        }
+        Some(password)
+    }
+}
+
+// `caching_sha2_password` status bytes sent in AuthMoreData packets
+const FAST_AUTH_SUCCESS: u8 = 0x03;
+const PERFORM_FULL_AUTHENTICATION: u8 = 0x04;
+// ...and the one a client sends to ask for the server's public key
+const REQUEST_PUBLIC_KEY: u8 = 0x02;
+
+impl<B: MysqlShim> MysqlIntermediary<B> {
+    /// Run the authentication exchange that follows the client's handshake response.
+    ///
+    /// Returns `false` if the client was denied, in which case it has already been sent an error.
+    pub(crate) async fn authenticate(
+        &mut self,
+        username: &str,
+        client_plugin: Option<&[u8]>,
+        auth_response: &[u8],
+        secure: bool,
+    ) -> io::Result<bool> {
+        let authenticator = match self.options.authenticator {
+            Some(ref authenticator) => authenticator.clone(),
+            None => return Ok(true),
+        };
+
+        let plugin = authenticator
+            .auth_plugin(username)
+            .await
+            .unwrap_or(self.options.auth_plugin);
+
+        // clients that predate plugins always answer with a native password scramble
+        let scramble = self.scramble;
+        let switched;
+        let auth_response = match client_plugin {
+            Some(name) if name != plugin.name().as_bytes() || !plugin.uses_initial_scramble() => {
+                writers::write_auth_switch_request(
+                    plugin.name(),
+                    &plugin.challenge(&scramble),
+                    &mut self.writer,
+                )?;
+                self.writer.flush_all().await?;
+                let (seq, packet) = self.next_handshake_packet().await?;
+                self.writer.set_seq(seq + 1);
+                switched = packet;
+                Some(&switched[..])
+            }
+            None if plugin != AuthPlugin::MysqlNativePassword => None,
+            _ => Some(auth_response),
+        };
+
+        let authenticated = match (plugin, auth_response) {
+            (_, None) => false,
+            (AuthPlugin::MysqlNativePassword, Some(auth_response)) => {
+                authenticator
+                    .authenticate(username, &scramble[..SCRAMBLE_LENGTH], auth_response)
+                    .await
+            }
+            (AuthPlugin::CachingSha2Password, Some(auth_response)) => {
+                self.caching_sha2_password(&*authenticator, username, auth_response, secure)
+                    .await?
+            }
+            (AuthPlugin::MysqlClearPassword, Some(auth_response)) => {
+                let password = auth_response.strip_suffix(&[0]).unwrap_or(auth_response);
+                authenticator.verify_password(username, password).await
+            }
+            #[cfg(feature = "ed25519")]
+            (AuthPlugin::ClientEd25519, Some(auth_response)) => {
+                match authenticator.ed25519_public_key(username).await {
+                    Some(public_key) => verify_ed25519(&public_key, &scramble, auth_response),
+                    None => false,
+                }
+            }
+        };
+
+        if !authenticated {
+            let using_password = match auth_response {
+                Some(auth_response) if !auth_response.is_empty() && auth_response != [0] => "YES",
+                _ => "NO",
+            };
+            // like MySQL, name the account the client was matched against
+            let host = match self.context.peer_addr {
+                Some(addr) => addr.ip().to_string(),
+                None => "localhost".to_owned(),
+            };
+            writers::write_err(
+                ErrorKind::ER_ACCESS_DENIED_ERROR,
+                format!(
+                    "Access denied for user '{}'@'{}' (using password: {})",
+                    username, host, using_password
+                )
+                .as_bytes(),
+                &mut self.writer,
+            )?;
+            self.writer.flush_all().await?;
+        }
+        Ok(authenticated)
+    }
+
+    async fn caching_sha2_password(
+        &mut self,
+        authenticator: &dyn Authenticator,
+        username: &str,
+        auth_response: &[u8],
+        secure: bool,
+    ) -> io::Result<bool> {
+        // clients do not scramble empty passwords
+        if auth_response.is_empty() {
+            return Ok(authenticator.verify_password(username, b"").await);
+        }
+
+        let cache = self.options.caching_sha2_password_cache.clone();
+        let scramble = &self.scramble[..SCRAMBLE_LENGTH];
+        if cache.verify(username, scramble, auth_response) {
+            writers::write_auth_more_data(&[FAST_AUTH_SUCCESS], &mut self.writer)?;
+            return Ok(true);
+        }
+
+        writers::write_auth_more_data(&[PERFORM_FULL_AUTHENTICATION], &mut self.writer)?;
+        self.writer.flush_all().await?;
+        let (seq, packet) = self.next_handshake_packet().await?;
+        self.writer.set_seq(seq + 1);
+
+        let mut password = if secure {
+            packet
+        } else {
+            // without TLS, the password has to be encrypted with our public key
+            let key = match self.options.rsa_key {
+                Some(ref key) => key.clone(),
+                None => return Ok(false),
+            };
+            let packet = if packet == [REQUEST_PUBLIC_KEY] {
+                writers::write_auth_more_data(key.public_pem.as_bytes(), &mut self.writer)?;
+                self.writer.flush_all().await?;
+                let (seq, packet) = self.next_handshake_packet().await?;
+                self.writer.set_seq(seq + 1);
+                packet
+            } else {
+                packet
+            };
+            match key.decrypt_password(&self.scramble[..SCRAMBLE_LENGTH], &packet) {
+                Some(password) => password,
+                None => return Ok(false),
+            }
+        };
+        if password.last() == Some(&0) {
+            password.pop();
+        }
+
+        let authenticated = authenticator.verify_password(username, &password).await;
+        if authenticated {
+            cache.insert(username, &password);
+        }
+        Ok(authenticated)
+    }
+}
+
+/// Compute the stored form of a `mysql_native_password` password, `SHA1(SHA1(password))`.
+///
+/// This is the value MySQL shows (hex-encoded, with a leading `*`) in the `authentication_string`
+/// column of `mysql.user`.
+pub fn native_password_hash(password: &[u8]) -> [u8; 20] {
+    Sha1::digest(Sha1::digest(password)).into()
+}
+
+/// Check a client's `mysql_native_password` response against a stored password hash.
+///
+/// The client sends `SHA1(password) XOR SHA1(scramble + SHA1(SHA1(password)))`. XOR-ing that with
+/// the second term, which the server can compute from the stored hash, recovers `SHA1(password)`,
+/// and hashing that once more must give the stored hash.
+pub fn verify_native_password(hash: &[u8; 20], scramble: &[u8], auth_response: &[u8]) -> bool {
+    if auth_response.len() != 20 {
+        return false;
+    }
+
+    let mask = Sha1::new()
+        .chain_update(scramble)
+        .chain_update(hash)
+        .finalize();
+    let mut candidate = [0u8; 20];
+    for i in 0..20 {
+        candidate[i] = auth_response[i] ^ mask[i];
+    }
+    constant_time_eq(&Sha1::digest(candidate), hash)
+}
+
+/// Compare `a` and `b` in time that only depends on their lengths, so that how long a password
+/// check takes says nothing about how close the client got.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Compute the `client_ed25519` public key for `password`, as MariaDB's `ed25519_password()`
+/// function does (before base64-encoding it).
+///
+/// The plugin uses the password in place of an Ed25519 seed: the secret scalar and nonce prefix
+/// are taken from `SHA512(password)`.
+#[cfg(feature = "ed25519")]
+pub fn ed25519_password(password: &[u8]) -> [u8; 32] {
+    use ed25519_dalek::hazmat::ExpandedSecretKey;
+    use ed25519_dalek::VerifyingKey;
+
+    let expanded = ExpandedSecretKey::from_bytes(&sha2::Sha512::digest(password).into());
+    VerifyingKey::from(&expanded).to_bytes()
+}
+
+/// Check a client's `client_ed25519` response, a signature of `scramble`, against the account's
+/// public key.
+#[cfg(feature = "ed25519")]
+pub fn verify_ed25519(public_key: &[u8; 32], scramble: &[u8], auth_response: &[u8]) -> bool {
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+    use std::convert::TryFrom;
+
+    let key = match VerifyingKey::from_bytes(public_key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let signature = match Signature::try_from(auth_response) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    key.verify(scramble, &signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRAMBLE: &[u8] = b";X,po_k}>o6^Wz!/kM}N";
+
+    #[test]
+    fn it_caches_sha2_passwords() {
+        let cache = CachingSha2PasswordCache::default();
+        let token = crate::myc::scramble::scramble_sha256(SCRAMBLE, b"hunter2").unwrap();
+        assert!(!cache.verify("jon", SCRAMBLE, &token));
+
+        cache.insert("jon", b"hunter2");
+        assert!(cache.verify("jon", SCRAMBLE, &token));
+        assert!(!cache.verify("bob", SCRAMBLE, &token));
+        let token = crate::myc::scramble::scramble_sha256(SCRAMBLE, b"hunter3").unwrap();
+        assert!(!cache.verify("jon", SCRAMBLE, &token));
+
+        cache.remove("jon");
+        let token = crate::myc::scramble::scramble_sha256(SCRAMBLE, b"hunter2").unwrap();
+        assert!(!cache.verify("jon", SCRAMBLE, &token));
+    }
+
+    #[test]
+    fn constant_time_eq_matches_equality() {
+        assert!(constant_time_eq(b"hunter2", b"hunter2"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter3"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn it_hashes_like_mysql() {
+        // SELECT PASSWORD('password') on MySQL 5.7 gives *2470C0C06DEE42FD1618BB99005ADCA2EC9D1E19
+        let expected = [
+            0x24, 0x70, 0xc0, 0xc0, 0x6d, 0xee, 0x42, 0xfd, 0x16, 0x18, 0xbb, 0x99, 0x00, 0x5a,
+            0xdc, 0xa2, 0xec, 0x9d, 0x1e, 0x19,
+        ];
+        assert_eq!(native_password_hash(b"password"), expected);
+    }
+
+    #[test]
+    fn it_verifies_native_password() {
+        let hash = native_password_hash(b"hunter2");
+        assert!(verify_native_password(
+            &hash,
+            SCRAMBLE,
+            &crate::myc::scramble::scramble_native(SCRAMBLE, b"hunter2").unwrap()
+        ));
+        assert!(!verify_native_password(
+            &hash,
+            SCRAMBLE,
+            &crate::myc::scramble::scramble_native(SCRAMBLE, b"hunter3").unwrap()
+        ));
+        assert!(!verify_native_password(&hash, SCRAMBLE, &[]));
+    }
+}
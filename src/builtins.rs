@@ -0,0 +1,329 @@
+use crate::myc::constants::StatusFlags;
+use crate::writers;
+use crate::{
+    Column, ColumnFlags, ColumnType, ErrorKind, MysqlIntermediary, MysqlShim, QueryResultWriter,
+};
+use std::io;
+
+/// Queries the server answers by itself rather than passing them on to the shim.
+pub(crate) enum Builtin {
+    /// `SELECT CONNECTION_ID()`
+    ConnectionId,
+    /// `SHOW [FULL] PROCESSLIST`, listing the connections the shim lets the client see
+    ProcessList { full: bool },
+    /// `SELECT * FROM performance_schema.session_connect_attrs` (or
+    /// `session_account_connect_attrs`), answered with the attributes of the current connection.
+    /// Queries with a column list or a filter are left to the shim.
+    ConnectAttrs,
+    /// `KILL [QUERY | CONNECTION] id`
+    Kill { id: u32, connection: bool },
+    /// `SHOW WARNINGS`, or with `count`, `SHOW COUNT(*) WARNINGS`
+    ShowWarnings { count: bool },
+    /// `SELECT @@[session.]warning_count`
+    WarningCount,
+}
+
+impl Builtin {
+    pub(crate) fn parse(q: &[u8]) -> Option<Builtin> {
+        if !starts_with_keyword(q, &[b"select", b"show", b"kill"]) {
+            return None;
+        }
+        let q = normalize(q);
+        match &q[..] {
+            b"select connection_id()" => Some(Builtin::ConnectionId),
+            b"show processlist" => Some(Builtin::ProcessList { full: false }),
+            b"show full processlist" => Some(Builtin::ProcessList { full: true }),
+            b"show warnings" => Some(Builtin::ShowWarnings { count: false }),
+            b"show count(*) warnings" => Some(Builtin::ShowWarnings { count: true }),
+            b"select @@warning_count" | b"select @@session.warning_count" => {
+                Some(Builtin::WarningCount)
+            }
+            b"select * from performance_schema.session_connect_attrs"
+            | b"select * from performance_schema.session_account_connect_attrs" => {
+                Some(Builtin::ConnectAttrs)
+            }
+            _ if q.starts_with(b"kill ") => {
+                let (connection, id) = match &q[b"kill ".len()..] {
+                    id if id.starts_with(b"query ") => (false, &id[b"query ".len()..]),
+                    id if id.starts_with(b"connection ") => (true, &id[b"connection ".len()..]),
+                    id => (true, id),
+                };
+                let id = std::str::from_utf8(id).ok()?.parse().ok()?;
+                Some(Builtin::Kill { id, connection })
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the query reports on the warnings of the previous statement, and so must not
+    /// replace them with its own.
+    pub(crate) fn is_diagnostic(&self) -> bool {
+        matches!(self, Builtin::ShowWarnings { .. } | Builtin::WarningCount)
+    }
+}
+
+/// Whether the first word of `q` is one of `keywords` (which must be lowercase), ignoring case.
+///
+/// This is cheap enough to run on every query, so that only the few that may be recognized need
+/// to be `normalize`d.
+pub(crate) fn starts_with_keyword(q: &[u8], keywords: &[&[u8]]) -> bool {
+    let start = q
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(q.len());
+    let q = &q[start..];
+    keywords.iter().any(|keyword| {
+        q.len() >= keyword.len()
+            && q[..keyword.len()].eq_ignore_ascii_case(keyword)
+            && q.get(keyword.len())
+                .is_none_or(|b| b.is_ascii_whitespace() || *b == b';')
+    })
+}
+
+/// Lowercase `q`, collapse runs of whitespace, and drop any trailing `;`.
+pub(crate) fn normalize(q: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(q.len());
+    for word in q
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|word| !word.is_empty())
+    {
+        if !normalized.is_empty() {
+            normalized.push(b' ');
+        }
+        normalized.extend(word.iter().map(u8::to_ascii_lowercase));
+    }
+    while normalized.last() == Some(&b';') {
+        normalized.pop();
+    }
+    normalized
+}
+
+fn column(table: &str, name: &str, coltype: ColumnType, colflags: ColumnFlags) -> Column {
+    Column {
+        table: table.to_owned(),
+        column: name.to_owned(),
+        coltype,
+        colflags,
+        ..Default::default()
+    }
+}
+
+impl<B: MysqlShim> MysqlIntermediary<B> {
+    pub(crate) async fn run_builtin(&mut self, builtin: Builtin) -> io::Result<()> {
+        let w = QueryResultWriter::new(&mut self.writer, false);
+        match builtin {
+            Builtin::ConnectionId => {
+                let cols = [column(
+                    "",
+                    "CONNECTION_ID()",
+                    ColumnType::MYSQL_TYPE_LONGLONG,
+                    ColumnFlags::UNSIGNED_FLAG | ColumnFlags::NOT_NULL_FLAG,
+                )];
+                let mut writer = w.start(&cols).await?;
+                writer.write_col(u64::from(self.context.connection_id))?;
+                writer.end_row()?;
+                writer.finish().await
+            }
+            Builtin::ProcessList { full } => {
+                let string = |name| {
+                    column(
+                        "",
+                        name,
+                        ColumnType::MYSQL_TYPE_VAR_STRING,
+                        ColumnFlags::empty(),
+                    )
+                };
+                let cols = [
+                    column(
+                        "",
+                        "Id",
+                        ColumnType::MYSQL_TYPE_LONGLONG,
+                        ColumnFlags::UNSIGNED_FLAG | ColumnFlags::NOT_NULL_FLAG,
+                    ),
+                    string("User"),
+                    string("Host"),
+                    string("db"),
+                    string("Command"),
+                    column(
+                        "",
+                        "Time",
+                        ColumnType::MYSQL_TYPE_LONG,
+                        ColumnFlags::NOT_NULL_FLAG,
+                    ),
+                    string("State"),
+                    string("Info"),
+                ];
+                let processes: Vec<_> = {
+                    let (shim, context) = (&self.shim, &self.context);
+                    let processes = self.options.connection_registry.processes();
+                    processes
+                        .into_iter()
+                        .filter(|process| shim.can_access_process(context, process))
+                        .collect()
+                };
+                let mut writer = w.start(&cols).await?;
+                for process in processes {
+                    let info = match process.info {
+                        Some(info) if !full => Some(info.chars().take(100).collect()),
+                        info => info,
+                    };
+                    writer.write_col(u64::from(process.id))?;
+                    writer.write_col(process.user)?;
+                    writer.write_col(process.host.map(|h| h.to_string()).unwrap_or_default())?;
+                    writer.write_col(process.database)?;
+                    writer.write_col(process.command)?;
+                    writer.write_col(process.time.as_secs() as i32)?;
+                    writer.write_col("")?;
+                    writer.write_col(info)?;
+                    writer.end_row()?;
+                }
+                writer.finish().await
+            }
+            Builtin::ConnectAttrs => {
+                let table = "session_connect_attrs";
+                let cols = [
+                    column(
+                        table,
+                        "PROCESSLIST_ID",
+                        ColumnType::MYSQL_TYPE_LONGLONG,
+                        ColumnFlags::UNSIGNED_FLAG,
+                    ),
+                    column(
+                        table,
+                        "ATTR_NAME",
+                        ColumnType::MYSQL_TYPE_VAR_STRING,
+                        ColumnFlags::NOT_NULL_FLAG,
+                    ),
+                    column(
+                        table,
+                        "ATTR_VALUE",
+                        ColumnType::MYSQL_TYPE_VAR_STRING,
+                        ColumnFlags::empty(),
+                    ),
+                    column(
+                        table,
+                        "ORDINAL_POSITION",
+                        ColumnType::MYSQL_TYPE_LONG,
+                        ColumnFlags::empty(),
+                    ),
+                ];
+                let mut writer = w.start(&cols).await?;
+                for (i, (name, value)) in self.connect_attrs.iter().enumerate() {
+                    writer.write_col(u64::from(self.context.connection_id))?;
+                    writer.write_col(name)?;
+                    writer.write_col(value)?;
+                    writer.write_col(i as i32)?;
+                    writer.end_row()?;
+                }
+                writer.finish().await
+            }
+            Builtin::Kill { id, connection } => self.kill(id, connection),
+            Builtin::ShowWarnings { count: false } => {
+                let cols = [
+                    column(
+                        "",
+                        "Level",
+                        ColumnType::MYSQL_TYPE_VAR_STRING,
+                        ColumnFlags::NOT_NULL_FLAG,
+                    ),
+                    column(
+                        "",
+                        "Code",
+                        ColumnType::MYSQL_TYPE_LONG,
+                        ColumnFlags::UNSIGNED_FLAG | ColumnFlags::NOT_NULL_FLAG,
+                    ),
+                    column(
+                        "",
+                        "Message",
+                        ColumnType::MYSQL_TYPE_VAR_STRING,
+                        ColumnFlags::NOT_NULL_FLAG,
+                    ),
+                ];
+                let mut writer = w.start(&cols).await?;
+                for warning in &self.warnings {
+                    writer.write_col(warning.level.as_str())?;
+                    writer.write_col(warning.code as u16 as u32)?;
+                    writer.write_col(&warning.message)?;
+                    writer.end_row()?;
+                }
+                writer.finish().await
+            }
+            Builtin::ShowWarnings { count: true } | Builtin::WarningCount => {
+                let name = if let Builtin::WarningCount = builtin {
+                    "@@warning_count"
+                } else {
+                    "@@session.warning_count"
+                };
+                let cols = [column(
+                    "",
+                    name,
+                    ColumnType::MYSQL_TYPE_LONGLONG,
+                    ColumnFlags::UNSIGNED_FLAG | ColumnFlags::NOT_NULL_FLAG,
+                )];
+                let mut writer = w.start(&cols).await?;
+                writer.write_col(self.warnings.len() as u64)?;
+                writer.end_row()?;
+                writer.finish().await
+            }
+        }
+    }
+
+    /// Interrupt the query of the connection with the given id, or close the connection
+    /// altogether, and tell the client whether there was such a connection that it may kill.
+    pub(crate) fn kill(&mut self, id: u32, connection: bool) -> io::Result<()> {
+        let registry = &self.options.connection_registry;
+        let allowed = registry
+            .process(id)
+            .map(|process| self.shim.can_access_process(&self.context, &process));
+        let found = match allowed {
+            Some(true) if connection => registry.kill_connection(id),
+            Some(true) => registry.kill_query(id),
+            Some(false) => {
+                return writers::write_err(
+                    ErrorKind::ER_KILL_DENIED_ERROR,
+                    format!("You are not owner of thread {}", id).as_bytes(),
+                    &mut self.writer,
+                );
+            }
+            None => false,
+        };
+        if !found {
+            writers::write_err(
+                ErrorKind::ER_NO_SUCH_THREAD,
+                format!("Unknown thread id: {}", id).as_bytes(),
+                &mut self.writer,
+            )
+        } else if connection && id == self.context.connection_id {
+            // like MySQL, a connection that kills itself is told so before it is closed
+            writers::write_err(
+                ErrorKind::ER_QUERY_INTERRUPTED,
+                b"Query execution was interrupted",
+                &mut self.writer,
+            )
+        } else {
+            writers::write_ok_packet(&mut self.writer, 0, 0, StatusFlags::empty())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_checks_the_leading_keyword() {
+        assert!(starts_with_keyword(b"  SeLeCt 1", &[b"select"]));
+        assert!(starts_with_keyword(
+            b"show\tprocesslist",
+            &[b"select", b"show"]
+        ));
+        assert!(starts_with_keyword(b"BEGIN;", &[b"begin"]));
+        assert!(starts_with_keyword(b"commit", &[b"commit"]));
+        assert!(!starts_with_keyword(b"selection", &[b"select"]));
+        assert!(!starts_with_keyword(
+            b"INSERT INTO t SELECT 1",
+            &[b"select"]
+        ));
+        assert!(!starts_with_keyword(b"", &[b"select"]));
+    }
+}
@@ -1,11 +1,60 @@
 use crate::myc::constants::{CapabilityFlags, Command as CommandByte};
+use std::borrow::Cow;
 
 #[derive(Debug)]
 pub struct ClientHandshake<'a> {
-    capabilities: CapabilityFlags,
-    maxps: u32,
-    collation: u16,
-    username: &'a [u8],
+    pub capabilities: CapabilityFlags,
+    pub maxps: u32,
+    pub collation: u16,
+    pub username: &'a [u8],
+    pub auth_response: &'a [u8],
+    pub database: Option<&'a [u8]>,
+    pub auth_plugin: Option<&'a [u8]>,
+    pub attributes: Vec<Attribute<'a>>,
+}
+
+/// The `COM_STMT_EXECUTE` flag asking for the results to be read through a cursor.
+const CURSOR_TYPE_READ_ONLY: u8 = 0x01;
+
+/// A connection attribute key and its value.
+pub type Attribute<'a> = (&'a [u8], &'a [u8]);
+
+/// A string that runs until a NUL byte or the end of the input, whichever comes first.
+fn null_terminated(i: &[u8]) -> nom::IResult<&[u8], &[u8]> {
+    match i.iter().position(|&b| b == 0) {
+        Some(end) => Ok((&i[end + 1..], &i[..end])),
+        None => Ok((&[], i)),
+    }
+}
+
+/// A length-encoded integer.
+fn lenenc_int(i: &[u8]) -> nom::IResult<&[u8], u64> {
+    let (i, first) = nom::number::complete::le_u8(i)?;
+    match first {
+        0xFC => nom::combinator::map(nom::number::complete::le_u16, u64::from)(i),
+        0xFD => nom::combinator::map(nom::number::complete::le_u24, u64::from)(i),
+        0xFE => nom::number::complete::le_u64(i),
+        _ => Ok((i, u64::from(first))),
+    }
+}
+
+/// A string prefixed by its length as a length-encoded integer.
+fn lenenc_str(i: &[u8]) -> nom::IResult<&[u8], &[u8]> {
+    let (i, len) = lenenc_int(i)?;
+    nom::bytes::complete::take(len as usize)(i)
+}
+
+/// The key/value pairs sent by clients with `CLIENT_CONNECT_ATTRS`.
+fn connect_attributes(i: &[u8]) -> nom::IResult<&[u8], Vec<Attribute<'_>>> {
+    let (i, mut attrs) = lenenc_str(i)?;
+    let mut attributes = Vec::new();
+    while !attrs.is_empty() {
+        let (rest, key) = lenenc_str(attrs)?;
+        let (rest, value) = lenenc_str(rest)?;
+        attributes.push((key, value));
+        attrs = rest;
+    }
+    Ok((i, attributes))
 }
 
 pub fn client_handshake(i: &[u8]) -> nom::IResult<&[u8], ClientHandshake<'_>> {
@@ -26,13 +75,49 @@ pub fn client_handshake(i: &[u8]) -> nom::IResult<&[u8], ClientHandshake<'_>> {
         let (i, username) = nom::bytes::complete::take_until(&b"\0"[..])(i)?;
         let (i, _) = nom::bytes::complete::tag(b"\0")(i)?;
 
+        let capabilities = CapabilityFlags::from_bits_truncate(cap);
+        let (i, auth_response) =
+            if capabilities.contains(CapabilityFlags::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA) {
+                lenenc_str(i)?
+            } else if capabilities.contains(CapabilityFlags::CLIENT_SECURE_CONNECTION) {
+                let (i, len) = nom::number::complete::le_u8(i)?;
+                nom::bytes::complete::take(len)(i)?
+            } else {
+                null_terminated(i)?
+            };
+        // some clients leave out trailing fields even though they set the matching capability
+        let (i, database) =
+            if capabilities.contains(CapabilityFlags::CLIENT_CONNECT_WITH_DB) && !i.is_empty() {
+                let (i, database) = null_terminated(i)?;
+                (i, Some(database))
+            } else {
+                (i, None)
+            };
+        let (i, auth_plugin) =
+            if capabilities.contains(CapabilityFlags::CLIENT_PLUGIN_AUTH) && !i.is_empty() {
+                let (i, auth_plugin) = null_terminated(i)?;
+                (i, Some(auth_plugin))
+            } else {
+                (i, None)
+            };
+        let (i, attributes) =
+            if capabilities.contains(CapabilityFlags::CLIENT_CONNECT_ATTRS) && !i.is_empty() {
+                connect_attributes(i)?
+            } else {
+                (i, Vec::new())
+            };
+
         Ok((
             i,
             ClientHandshake {
-                capabilities: CapabilityFlags::from_bits_truncate(cap),
+                capabilities,
                 maxps,
                 collation: u16::from(collation[0]),
                 username,
+                auth_response,
+                database,
+                auth_plugin,
+                attributes,
             },
         ))
     } else {
@@ -41,6 +126,8 @@ pub fn client_handshake(i: &[u8]) -> nom::IResult<&[u8], ClientHandshake<'_>> {
         let (i, maxps2) = nom::number::complete::le_u8(i)?;
         let maxps = (maxps2 as u32) << 16 | maxps1 as u32;
         let (i, username) = nom::bytes::complete::take_until(&b"\0"[..])(i)?;
+        let (i, _) = nom::bytes::complete::tag(b"\0")(i)?;
+        let (i, auth_response) = null_terminated(i)?;
 
         Ok((
             i,
@@ -49,11 +136,86 @@ pub fn client_handshake(i: &[u8]) -> nom::IResult<&[u8], ClientHandshake<'_>> {
                 maxps,
                 collation: 0,
                 username,
+                auth_response,
+                database: None,
+                auth_plugin: None,
+                attributes: Vec::new(),
             },
         ))
     }
 }
 
+/// The body of a `COM_CHANGE_USER` command.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChangeUser<'a> {
+    pub username: &'a [u8],
+    pub auth_response: &'a [u8],
+    pub database: Option<&'a [u8]>,
+    pub collation: Option<u16>,
+    pub auth_plugin: Option<&'a [u8]>,
+    pub attributes: Vec<Attribute<'a>>,
+}
+
+/// Parse the body of a `COM_CHANGE_USER` sent by a client with the given `capabilities`.
+pub fn change_user(i: &[u8], capabilities: CapabilityFlags) -> nom::IResult<&[u8], ChangeUser<'_>> {
+    // https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_com_change_user.html
+    let (i, username) = null_terminated(i)?;
+    let (i, auth_response) = if capabilities.contains(CapabilityFlags::CLIENT_SECURE_CONNECTION) {
+        let (i, len) = nom::number::complete::le_u8(i)?;
+        nom::bytes::complete::take(len)(i)?
+    } else {
+        null_terminated(i)?
+    };
+    let (i, database) = null_terminated(i)?;
+    let database = if database.is_empty() {
+        None
+    } else {
+        Some(database)
+    };
+    // everything after the database is optional
+    let (i, collation) = if i.len() >= 2 {
+        let (i, collation) = nom::number::complete::le_u16(i)?;
+        (i, Some(collation))
+    } else {
+        (i, None)
+    };
+    let (i, auth_plugin) =
+        if capabilities.contains(CapabilityFlags::CLIENT_PLUGIN_AUTH) && !i.is_empty() {
+            let (i, auth_plugin) = null_terminated(i)?;
+            (i, Some(auth_plugin))
+        } else {
+            (i, None)
+        };
+    let (i, attributes) =
+        if capabilities.contains(CapabilityFlags::CLIENT_CONNECT_ATTRS) && !i.is_empty() {
+            connect_attributes(i)?
+        } else {
+            (i, Vec::new())
+        };
+
+    Ok((
+        i,
+        ChangeUser {
+            username,
+            auth_response,
+            database,
+            collation,
+            auth_plugin,
+            attributes,
+        },
+    ))
+}
+
+/// Whether `i` is an `SSLRequest`, which a client sends instead of its handshake response when it
+/// wants to upgrade the connection to TLS. It is a `HandshakeResponse41` cut off right before the
+/// username.
+#[cfg(feature = "tls")]
+pub fn is_ssl_request(i: &[u8]) -> bool {
+    i.len() == 32
+        && CapabilityFlags::from_bits_truncate(u32::from_le_bytes([i[0], i[1], i[2], i[3]]))
+            .contains(CapabilityFlags::CLIENT_SSL)
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Command<'a> {
     Query(&'a [u8]),
@@ -63,22 +225,96 @@ pub enum Command<'a> {
     Init(&'a [u8]),
     Execute {
         stmt: u32,
+        /// Whether the client asked for a read-only cursor.
+        cursor: bool,
         params: &'a [u8],
     },
+    Fetch {
+        stmt: u32,
+        rows: u32,
+    },
     SendLongData {
         stmt: u32,
         param: u16,
         data: &'a [u8],
     },
+    ProcessKill(u32),
+    /// A `COM_CHANGE_USER`, whose body can only be parsed with
+    /// [`change_user`](fn.change_user.html) once the client's capabilities are known.
+    ChangeUser(&'a [u8]),
+    ResetConnection,
+    ResetStmt(u32),
+    SetOption(u16),
+    Statistics,
+    Debug,
     Ping,
     Quit,
+    /// A command the server handles itself, but whose body it could not parse, given as the
+    /// command byte.
+    Malformed(u8),
+    /// A command the server does not handle itself, given as the command byte and the rest of
+    /// the packet.
+    Unknown(u8, &'a [u8]),
+}
+
+impl<'a> Command<'a> {
+    /// The name MySQL shows for this command in `SHOW PROCESSLIST`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Command::Query(_) => "Query",
+            Command::ListFields(_) => "Field List",
+            Command::Close(_) => "Close stmt",
+            Command::Prepare(_) => "Prepare",
+            Command::Init(_) => "Init DB",
+            Command::Execute { .. } => "Execute",
+            Command::Fetch { .. } => "Fetch",
+            Command::SendLongData { .. } => "Long Data",
+            Command::ProcessKill(_) => "Kill",
+            Command::ChangeUser(_) => "Change user",
+            Command::ResetConnection => "Reset connection",
+            Command::ResetStmt(_) => "Reset stmt",
+            Command::SetOption(_) => "Set option",
+            Command::Statistics => "Statistics",
+            Command::Debug => "Debug",
+            Command::Ping => "Ping",
+            Command::Quit => "Quit",
+            Command::Malformed(_) | Command::Unknown(..) => "Unknown",
+        }
+    }
+
+    /// The query text this command carries, if any.
+    pub fn query(&self) -> Option<Cow<'a, str>> {
+        match *self {
+            Command::Query(q) | Command::Prepare(q) => Some(String::from_utf8_lossy(q)),
+            _ => None,
+        }
+    }
+}
+
+/// Split the body of a `COM_FIELD_LIST` command into the table and the column wildcard.
+pub fn list_fields(i: &[u8]) -> (&[u8], &[u8]) {
+    let (wildcard, table) = null_terminated(i).unwrap();
+    (table, wildcard)
 }
 
 pub fn execute(i: &[u8]) -> nom::IResult<&[u8], Command<'_>> {
     let (i, stmt) = nom::number::complete::le_u32(i)?;
-    let (i, _flags) = nom::bytes::complete::take(1u8)(i)?;
+    let (i, flags) = nom::number::complete::le_u8(i)?;
     let (i, _iterations) = nom::number::complete::le_u32(i)?;
-    Ok((&[], Command::Execute { stmt, params: i }))
+    Ok((
+        &[],
+        Command::Execute {
+            stmt,
+            cursor: flags & CURSOR_TYPE_READ_ONLY != 0,
+            params: i,
+        },
+    ))
+}
+
+pub fn fetch(i: &[u8]) -> nom::IResult<&[u8], Command<'_>> {
+    let (i, stmt) = nom::number::complete::le_u32(i)?;
+    let (i, rows) = nom::number::complete::le_u32(i)?;
+    Ok((i, Command::Fetch { stmt, rows }))
 }
 
 pub fn send_long_data(i: &[u8]) -> nom::IResult<&[u8], Command<'_>> {
@@ -94,6 +330,33 @@ pub fn send_long_data(i: &[u8]) -> nom::IResult<&[u8], Command<'_>> {
     ))
 }
 
+/// The commands `parse` recognizes.
+const COMMANDS: [CommandByte; 17] = [
+    CommandByte::COM_QUERY,
+    CommandByte::COM_FIELD_LIST,
+    CommandByte::COM_INIT_DB,
+    CommandByte::COM_STMT_PREPARE,
+    CommandByte::COM_STMT_EXECUTE,
+    CommandByte::COM_STMT_FETCH,
+    CommandByte::COM_STMT_SEND_LONG_DATA,
+    CommandByte::COM_STMT_CLOSE,
+    CommandByte::COM_PROCESS_KILL,
+    CommandByte::COM_CHANGE_USER,
+    CommandByte::COM_STMT_RESET,
+    CommandByte::COM_SET_OPTION,
+    CommandByte::COM_RESET_CONNECTION,
+    CommandByte::COM_STATISTICS,
+    CommandByte::COM_DEBUG,
+    CommandByte::COM_QUIT,
+    CommandByte::COM_PING,
+];
+
+/// Whether the client waits for a response to the command with the given command byte.
+pub fn expects_response(command: u8) -> bool {
+    command != CommandByte::COM_STMT_CLOSE as u8
+        && command != CommandByte::COM_STMT_SEND_LONG_DATA as u8
+}
+
 pub fn parse(i: &[u8]) -> nom::IResult<&[u8], Command<'_>> {
     use nom::bytes::complete::tag;
     use nom::combinator::{map, rest};
@@ -116,6 +379,7 @@ pub fn parse(i: &[u8]) -> nom::IResult<&[u8], Command<'_>> {
             Command::Prepare,
         ),
         preceded(tag(&[CommandByte::COM_STMT_EXECUTE as u8]), execute),
+        preceded(tag(&[CommandByte::COM_STMT_FETCH as u8]), fetch),
         preceded(
             tag(&[CommandByte::COM_STMT_SEND_LONG_DATA as u8]),
             send_long_data,
@@ -127,8 +391,52 @@ pub fn parse(i: &[u8]) -> nom::IResult<&[u8], Command<'_>> {
             ),
             Command::Close,
         ),
+        map(
+            preceded(
+                tag(&[CommandByte::COM_PROCESS_KILL as u8]),
+                nom::number::complete::le_u32,
+            ),
+            Command::ProcessKill,
+        ),
+        map(
+            preceded(tag(&[CommandByte::COM_CHANGE_USER as u8]), rest),
+            Command::ChangeUser,
+        ),
+        map(
+            preceded(
+                tag(&[CommandByte::COM_STMT_RESET as u8]),
+                nom::number::complete::le_u32,
+            ),
+            Command::ResetStmt,
+        ),
+        map(
+            preceded(
+                tag(&[CommandByte::COM_SET_OPTION as u8]),
+                nom::number::complete::le_u16,
+            ),
+            Command::SetOption,
+        ),
+        map(tag(&[CommandByte::COM_RESET_CONNECTION as u8]), |_| {
+            Command::ResetConnection
+        }),
+        map(tag(&[CommandByte::COM_STATISTICS as u8]), |_| {
+            Command::Statistics
+        }),
+        map(tag(&[CommandByte::COM_DEBUG as u8]), |_| Command::Debug),
         map(tag(&[CommandByte::COM_QUIT as u8]), |_| Command::Quit),
         map(tag(&[CommandByte::COM_PING as u8]), |_| Command::Ping),
+        // a command that gets here either is one of the above with a body that is too short, or
+        // is not one the server handles itself
+        map(
+            nom::sequence::pair(nom::number::complete::le_u8, rest),
+            |(cmd, data)| {
+                if COMMANDS.iter().any(|&known| known as u8 == cmd) {
+                    Command::Malformed(cmd)
+                } else {
+                    Command::Unknown(cmd, data)
+                }
+            },
+        ),
     ))(i)
 }
 
@@ -136,8 +444,7 @@ pub fn parse(i: &[u8]) -> nom::IResult<&[u8], Command<'_>> {
 mod tests {
     use super::*;
     use crate::myc::constants::{CapabilityFlags, UTF8_GENERAL_CI};
-    use crate::packet::PacketReader;
-    use std::io::Cursor;
+    use crate::packet::onepacket;
 
     #[test]
     fn it_parses_handshake() {
@@ -146,10 +453,8 @@ mod tests {
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x6a, 0x6f, 0x6e, 0x00, 0x00,
         ];
-        let r = Cursor::new(&data[..]);
-        let mut pr = PacketReader::new(r);
-        let (_, p) = pr.next().unwrap().unwrap();
-        let (_, handshake) = client_handshake(&p).unwrap();
+        let (_, (_, p)) = onepacket(&data[..]).unwrap();
+        let (_, handshake) = client_handshake(p).unwrap();
         println!("{:?}", handshake);
         assert!(handshake
             .capabilities
@@ -166,6 +471,82 @@ mod tests {
         assert_eq!(handshake.collation, UTF8_GENERAL_CI);
         assert_eq!(handshake.username, &b"jon"[..]);
         assert_eq!(handshake.maxps, 16777216);
+        assert_eq!(handshake.auth_response, &b""[..]);
+        assert_eq!(handshake.database, None);
+        assert_eq!(handshake.auth_plugin, None);
+        assert!(handshake.attributes.is_empty());
+    }
+
+    #[test]
+    fn it_parses_full_handshake() {
+        // shaped like what libmysqlclient 8.0 sends for `mysql -u jon -D db`
+        let mut data = vec![
+            0x0d, 0xa2, 0x3a, 0x00, 0x00, 0x00, 0x00, 0x01, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        data.extend(b"jon\0");
+        data.push(20);
+        data.extend(&[0xaa; 20]);
+        data.extend(b"db\0caching_sha2_password\0");
+        let attrs = b"\x04_pid\x0512345\x0c_client_name\x08libmysql\x00\x00";
+        data.push(attrs.len() as u8);
+        data.extend(&attrs[..]);
+
+        let (rest, handshake) = client_handshake(&data).unwrap();
+        assert!(rest.is_empty());
+        assert!(handshake
+            .capabilities
+            .contains(CapabilityFlags::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA));
+        assert_eq!(handshake.collation, 0xff);
+        assert_eq!(handshake.username, &b"jon"[..]);
+        assert_eq!(handshake.auth_response, &[0xaa; 20][..]);
+        assert_eq!(handshake.database, Some(&b"db"[..]));
+        assert_eq!(handshake.auth_plugin, Some(&b"caching_sha2_password"[..]));
+        assert_eq!(
+            handshake.attributes,
+            vec![
+                (&b"_pid"[..], &b"12345"[..]),
+                (&b"_client_name"[..], &b"libmysql"[..]),
+                (&b""[..], &b""[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_auth_response_encodings() {
+        let mut base = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x21];
+        base.extend(&[0; 23]);
+        base.extend(b"jon\0");
+        let with_caps = |caps: u32, auth: &[u8]| {
+            let mut data = base.clone();
+            data[..4].copy_from_slice(&(caps | 0x200).to_le_bytes());
+            data.extend(auth);
+            data
+        };
+
+        // CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA, with a 251-byte response needing a 3-byte length
+        let mut auth = vec![0xfc, 251, 0];
+        auth.extend(&[0x01; 251]);
+        let data = with_caps(0x0020_0000, &auth);
+        assert_eq!(
+            client_handshake(&data).unwrap().1.auth_response,
+            &[0x01; 251][..]
+        );
+
+        // CLIENT_SECURE_CONNECTION
+        let data = with_caps(0x8000, b"\x03abcdef");
+        assert_eq!(
+            client_handshake(&data).unwrap().1.auth_response,
+            &b"abc"[..]
+        );
+
+        // neither, so NUL-terminated
+        let data = with_caps(0, b"abc\0");
+        assert_eq!(
+            client_handshake(&data).unwrap().1.auth_response,
+            &b"abc"[..]
+        );
     }
 
     #[test]
@@ -175,10 +556,8 @@ mod tests {
             0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x5f, 0x63, 0x6f, 0x6d, 0x6d, 0x65, 0x6e,
             0x74, 0x20, 0x6c, 0x69, 0x6d, 0x69, 0x74, 0x20, 0x31,
         ];
-        let r = Cursor::new(&data[..]);
-        let mut pr = PacketReader::new(r);
-        let (_, p) = pr.next().unwrap().unwrap();
-        let (_, cmd) = parse(&p).unwrap();
+        let (_, (_, p)) = onepacket(&data[..]).unwrap();
+        let (_, cmd) = parse(p).unwrap();
         assert_eq!(
             cmd,
             Command::Query(&b"select @@version_comment limit 1"[..])
@@ -195,13 +574,63 @@ mod tests {
             0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x5f, 0x63, 0x6f, 0x6d, 0x6d, 0x65, 0x6e,
             0x74, 0x20, 0x6c, 0x69, 0x6d, 0x69, 0x74, 0x20, 0x31,
         ];
-        let r = Cursor::new(&data[..]);
-        let mut pr = PacketReader::new(r);
-        let (_, p) = pr.next().unwrap().unwrap();
-        let (_, cmd) = parse(&p).unwrap();
+        let (_, (_, p)) = onepacket(&data[..]).unwrap();
+        let (_, cmd) = parse(p).unwrap();
         assert_eq!(
             cmd,
             Command::ListFields(&b"select @@version_comment limit 1"[..])
         );
     }
+
+    #[test]
+    fn it_handles_unknown_commands() {
+        // COM_TIME
+        assert_eq!(parse(&[0x0f]).unwrap().1, Command::Unknown(0x0f, &[]));
+        // a COM_STMT_EXECUTE too short to be one
+        assert_eq!(parse(&[0x17, 0x01]).unwrap().1, Command::Malformed(0x17));
+        assert!(expects_response(0x17));
+        // a COM_STMT_CLOSE without a statement id
+        assert_eq!(parse(&[0x19]).unwrap().1, Command::Malformed(0x19));
+        assert!(!expects_response(0x19));
+        assert!(parse(&[]).is_err());
+    }
+
+    #[test]
+    fn it_parses_change_user() {
+        let capabilities = CapabilityFlags::CLIENT_PROTOCOL_41
+            | CapabilityFlags::CLIENT_SECURE_CONNECTION
+            | CapabilityFlags::CLIENT_PLUGIN_AUTH
+            | CapabilityFlags::CLIENT_CONNECT_ATTRS;
+        let mut data = b"\x11bob\0\x03abcshop\0\x21\x00mysql_native_password\0".to_vec();
+        data.extend(b"\x08\x04_pid\x0242");
+        let (_, cmd) = parse(&data).unwrap();
+        let data = match cmd {
+            Command::ChangeUser(data) => data,
+            cmd => panic!("expected COM_CHANGE_USER, got {:?}", cmd),
+        };
+        let (_, change) = change_user(data, capabilities).unwrap();
+        assert_eq!(
+            change,
+            ChangeUser {
+                username: b"bob",
+                auth_response: b"abc",
+                database: Some(b"shop"),
+                collation: Some(0x21),
+                auth_plugin: Some(b"mysql_native_password"),
+                attributes: vec![(&b"_pid"[..], &b"42"[..])],
+            }
+        );
+
+        // clients predating 4.1 stop after the database
+        let (_, change) = change_user(b"bob\0abc\0\0", CapabilityFlags::empty()).unwrap();
+        assert_eq!(change.auth_response, b"abc");
+        assert_eq!(change.database, None);
+        assert_eq!(change.collation, None);
+    }
+
+    #[test]
+    fn it_splits_list_fields() {
+        assert_eq!(list_fields(b"users\0na%"), (&b"users"[..], &b"na%"[..]));
+        assert_eq!(list_fields(b"users"), (&b"users"[..], &b""[..]));
+    }
 }
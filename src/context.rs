@@ -0,0 +1,53 @@
+use crate::myc::constants::CapabilityFlags;
+use crate::CancellationToken;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// What the server knows about a connected client, as given to
+/// [`MysqlShim::on_connect`](trait.MysqlShim.html#method.on_connect).
+#[derive(Debug, Clone)]
+pub struct ConnectionContext {
+    /// The user the client authenticated as.
+    pub username: String,
+    /// The database the client asked to start out in with `CLIENT_CONNECT_WITH_DB`, if any.
+    pub database: Option<String>,
+    /// The connection attributes the client sent with `CLIENT_CONNECT_ATTRS`, such as
+    /// `_client_name` or `program_name`.
+    pub attributes: HashMap<String, String>,
+    /// The capabilities the client claimed in its handshake response.
+    pub capabilities: CapabilityFlags,
+    /// The character set and collation the client asked for.
+    pub collation: u16,
+    /// The size of the largest packet the client said it can receive.
+    pub max_packet_size: u32,
+    /// The address of the client, if the connection is over TCP.
+    pub peer_addr: Option<SocketAddr>,
+    /// The id the server gave this connection in its initial handshake.
+    pub connection_id: u32,
+    /// Whether the connection was upgraded to TLS.
+    pub secure: bool,
+    /// Fires when the query this connection is running is killed with `KILL QUERY` or
+    /// `KILL CONNECTION`.
+    pub cancellation: CancellationToken,
+}
+
+impl ConnectionContext {
+    pub(crate) fn new(
+        connection_id: u32,
+        peer_addr: Option<SocketAddr>,
+        cancellation: CancellationToken,
+    ) -> Self {
+        ConnectionContext {
+            username: String::new(),
+            database: None,
+            attributes: HashMap::new(),
+            capabilities: CapabilityFlags::empty(),
+            collation: 0,
+            max_packet_size: 0,
+            peer_addr,
+            connection_id,
+            secure: false,
+            cancellation,
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use crate::ErrorKind;
+use std::io;
+
+/// Errors that a [`MysqlShim`](trait.MysqlShim.html) can return while handling a command.
+///
+/// When a shim fails, the intermediary asks the error how to describe itself to the client. If it
+/// can be described, and nothing has been sent in response to the command yet, the client gets an
+/// ERR packet and the connection stays open, along with any statements it has prepared.
+/// Otherwise the connection is closed, and the error is returned from
+/// [`MysqlIntermediary::run_on`](struct.MysqlIntermediary.html#method.run_on).
+///
+/// Errors that wrap an `io::Error` from the connection itself (for example, one that occurred
+/// while writing results) should be handed back, since the client can no longer be talked to.
+pub trait IntoMysqlError: Sized {
+    /// The error code and message to send the client, or `Err(self)` if the error is fatal to
+    /// the connection.
+    fn into_mysql_error(self) -> Result<(ErrorKind, String), Self>;
+}
+
+/// Errors about the connection going away are fatal. Any other kind of error is sent to the client
+/// as `ER_UNKNOWN_ERROR`, with the error's description as the message.
+impl IntoMysqlError for io::Error {
+    fn into_mysql_error(self) -> Result<(ErrorKind, String), Self> {
+        match self.kind() {
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::WriteZero => Err(self),
+            _ => Ok((ErrorKind::ER_UNKNOWN_ERROR, self.to_string())),
+        }
+    }
+}
@@ -47,12 +47,14 @@
 //!                 column: "a".to_string(),
 //!                 coltype: ColumnType::MYSQL_TYPE_LONGLONG,
 //!                 colflags: ColumnFlags::empty(),
+//!                 ..Default::default()
 //!             },
 //!             Column {
 //!                 table: "foo".to_string(),
 //!                 column: "b".to_string(),
 //!                 coltype: ColumnType::MYSQL_TYPE_STRING,
 //!                 colflags: ColumnFlags::empty(),
+//!                 ..Default::default()
 //!             },
 //!         ];
 //!
@@ -100,14 +102,34 @@ use std::io;
 use std::io::prelude::*;
 use std::iter;
 
-pub use crate::myc::constants::{ColumnFlags, ColumnType, StatusFlags};
+pub use crate::myc::constants::{CapabilityFlags, ColumnFlags, ColumnType, StatusFlags};
+/// The RSA implementation used for `caching_sha2_password`, re-exported so that a key can be
+/// loaded or generated for
+/// [`ServerOptions::with_rsa_key`](struct.ServerOptions.html#method.with_rsa_key).
+pub use rsa;
+/// The rustls version used for TLS support, re-exported so that a compatible `ServerConfig` can
+/// be built.
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
 
+mod auth;
+mod builtins;
 mod commands;
+mod context;
+mod error;
 mod errorcodes;
+mod options;
 mod packet;
 mod params;
+mod registry;
 mod resultset;
+mod session;
+mod split;
+#[cfg(feature = "tls")]
+mod tls;
+mod transaction;
 mod value;
+mod warning;
 mod writers;
 
 /// Meta-information abot a single column, used either to describe a prepared statement parameter
@@ -128,14 +150,72 @@ pub struct Column {
     ///
     /// Of particular interest are `ColumnFlags::UNSIGNED_FLAG` and `ColumnFlags::NOT_NULL_FLAG`.
     pub colflags: ColumnFlags,
+    /// The schema (database) this column's table is in.
+    pub schema: Option<String>,
+    /// The name of this column's table, if `table` is an alias.
+    pub org_table: Option<String>,
+    /// The name of this column, if `column` is an alias.
+    pub org_column: Option<String>,
+    /// The collation of this column's values, which defaults to `utf8_general_ci` (33).
+    ///
+    /// Clients take `binary` (63) to mean that the values are bytes rather than text, as they are
+    /// for numbers and `BLOB`s.
+    pub character_set: Option<u16>,
+    /// The maximum length of this column's values, which defaults to 1024.
+    ///
+    /// Clients use this as the precision of numbers and as the display width of strings.
+    pub column_length: Option<u32>,
+    /// The number of digits after the decimal point of this column's values, for `DECIMAL`,
+    /// floating-point and temporal columns. Defaults to 0.
+    pub decimals: Option<u8>,
+    /// This column's default value, which is only sent in response to `COM_FIELD_LIST`.
+    pub default_value: Option<String>,
+}
+
+/// A `VARCHAR` column with no name, no flags, and none of the optional metadata, meant to be
+/// completed with struct update syntax.
+impl Default for Column {
+    fn default() -> Self {
+        Column {
+            table: String::new(),
+            column: String::new(),
+            coltype: ColumnType::MYSQL_TYPE_VAR_STRING,
+            colflags: ColumnFlags::empty(),
+            schema: None,
+            org_table: None,
+            org_column: None,
+            character_set: None,
+            column_length: None,
+            decimals: None,
+            default_value: None,
+        }
+    }
 }
 
+#[cfg(feature = "ed25519")]
+pub use crate::auth::{ed25519_password, verify_ed25519};
+pub use crate::auth::{
+    native_password_hash, verify_native_password, AuthPlugin, Authenticator,
+    CachingSha2PasswordCache, NativePasswordAuthenticator,
+};
+pub use crate::context::ConnectionContext;
+pub use crate::error::IntoMysqlError;
 pub use crate::errorcodes::ErrorKind;
+pub use crate::options::ServerOptions;
 use crate::packet::PacketBuff;
 pub use crate::params::{ParamParser, ParamValue, Params};
-pub use crate::resultset::{InitWriter, QueryResultWriter, RowWriter, StatementMetaWriter};
+pub use crate::registry::{CancellationToken, ConnectionRegistry, ProcessInfo, Statistics};
+pub use crate::resultset::{
+    InitWriter, ListFieldsWriter, QueryResultWriter, RowStream, RowWriter, StatementMetaWriter,
+};
+pub use crate::session::SessionStateChange;
 pub use crate::value::{ToMysqlValue, Value, ValueInner};
+pub use crate::warning::{Warning, WarningLevel};
 use async_trait::async_trait;
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 
 /// Implementors of this trait can be used to drive a MySQL-compatible database backend.
@@ -143,8 +223,10 @@ use tokio::net::TcpStream;
 pub trait MysqlShim: Send {
     /// The error type produced by operations on this shim.
     ///
-    /// Must implement `From<io::Error>` so that transport-level errors can be lifted.
-    type Error: From<io::Error>;
+    /// Must implement `From<io::Error>` so that transport-level errors can be lifted, and
+    /// [`IntoMysqlError`](trait.IntoMysqlError.html) so that errors returned while handling a
+    /// command can be reported to the client without closing the connection.
+    type Error: From<io::Error> + IntoMysqlError;
 
     /// Called when the client issues a request to prepare `query` for later execution.
     ///
@@ -189,16 +271,113 @@ pub trait MysqlShim: Send {
         Ok(())
     }
 
+    /// Called once a client has logged in, before it is told that it did.
+    ///
+    /// `context` describes the connection; implementors should keep whatever parts of it they
+    /// need, for example to route queries based on the user or the initial database.
+    async fn on_connect(&mut self, _context: &ConnectionContext) {}
+
+    /// Called when the client logs in again as a different user with `COM_CHANGE_USER`, before
+    /// it is told that it did.
+    ///
+    /// `context` describes the connection as it is for the new user. Any session state kept for
+    /// the previous user should be reset; the statements it prepared have already been closed.
+    async fn on_change_user(&mut self, _context: &ConnectionContext) {}
+
+    /// Called when the client asks for the columns of `table` with `COM_FIELD_LIST`, as the
+    /// `mysql` command line client does for tab completion.
+    ///
+    /// Only columns whose name matches `wildcard`, a `LIKE` pattern, should be listed; it is empty
+    /// if the client wants all of them. The columns should be given using the provided
+    /// [`ListFieldsWriter`](struct.ListFieldsWriter.html). The default lists a single placeholder
+    /// column.
+    async fn on_list_fields(
+        &mut self,
+        _table: &str,
+        _wildcard: &str,
+        writer: ListFieldsWriter<'_>,
+    ) -> Result<(), Self::Error> {
+        let cols = &[Column {
+            table: String::new(),
+            column: "not implemented".to_owned(),
+            coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
+            colflags: myc::constants::ColumnFlags::UNSIGNED_FLAG,
+            ..Default::default()
+        }];
+        writer.reply(cols)?;
+        Ok(())
+    }
+
+    /// Called when the client resets a previously prepared statement, discarding any long data it
+    /// sent for its parameters.
+    async fn on_reset_stmt(&mut self, _stmt: u32) {}
+
+    /// Called when the client resets its session with `COM_RESET_CONNECTION`, typically when a
+    /// connection pool takes the connection back.
+    ///
+    /// Any session state, such as user variables or temporary tables, should be reset; the
+    /// statements the client prepared have already been closed.
+    async fn on_reset(&mut self) {}
+
+    /// Called when the client asks for a human-readable summary of the server's status with
+    /// `COM_STATISTICS` (as `mysqladmin status` does), and returns the string to send it.
+    ///
+    /// The default describes the counters the server keeps itself in `statistics`, which cover
+    /// all the connections that share this one's
+    /// [`ConnectionRegistry`](struct.ConnectionRegistry.html). It is also sent if this returns an
+    /// empty string.
+    async fn on_statistics(&mut self, statistics: Statistics) -> String {
+        statistics.to_string()
+    }
+
+    /// Called to decide whether the client in `context` may see the connection described by
+    /// `process` in `SHOW PROCESSLIST`, and kill it with `KILL` or `COM_PROCESS_KILL`.
+    ///
+    /// The default only gives clients access to connections of the user they logged in as, like
+    /// MySQL does for users without the `PROCESS` and `CONNECTION_ADMIN` privileges. Other
+    /// connections are left out of the process list, and killing them fails with
+    /// `ER_KILL_DENIED_ERROR`.
+    fn can_access_process(&self, context: &ConnectionContext, process: &ProcessInfo) -> bool {
+        process.user == context.username
+    }
+
     /// Called when client switches database.
     async fn on_init(&mut self, _: &str, _: InitWriter<'_>) -> Result<(), Self::Error> {
         Ok(())
     }
+
+    /// Called when the client sends a command the server does not implement, with the command
+    /// byte in `command` and the rest of the packet in `data`.
+    ///
+    /// The default replies with `ER_UNKNOWN_COM_ERROR`, like MySQL does for commands it does not
+    /// know.
+    async fn on_unknown_command(
+        &mut self,
+        _command: u8,
+        _data: &[u8],
+        results: QueryResultWriter<'_>,
+    ) -> Result<(), Self::Error> {
+        results
+            .error(ErrorKind::ER_UNKNOWN_COM_ERROR, b"Unknown command")
+            .await?;
+        Ok(())
+    }
 }
 
 /// A server that speaks the MySQL/MariaDB protocol, and can delegate client commands to a backend
 /// that implements [`MysqlShim`](trait.MysqlShim.html).
 pub struct MysqlIntermediary<B> {
     shim: B,
+    options: ServerOptions,
+    context: ConnectionContext,
+    registration: registry::Registration,
+    // the connection attributes in the order the client sent them
+    connect_attrs: Vec<(String, String)>,
+    // whether COM_QUERY can carry more than one statement
+    multi_statements: bool,
+    // the warnings raised by the last statement, for SHOW WARNINGS
+    warnings: Vec<Warning>,
+    scramble: [u8; 32],
     reader: packet::PacketBuff,
     writer: packet::PacketWriter,
 }
@@ -208,216 +387,740 @@ impl<B: MysqlShim> MysqlIntermediary<B> {
     /// disconnects or an error occurs. See also
     /// [`MysqlIntermediary::run_on`](struct.MysqlIntermediary.html#method.run_on).
     pub async fn run_on_tcp(shim: B, stream: TcpStream) -> Result<(), B::Error> {
-        MysqlIntermediary::run_on(shim, stream).await
+        MysqlIntermediary::run_on_tcp_with_options(shim, stream, ServerOptions::default()).await
+    }
+
+    /// Like [`run_on_tcp`](struct.MysqlIntermediary.html#method.run_on_tcp), but with the server
+    /// configured by the given [`ServerOptions`](struct.ServerOptions.html).
+    pub async fn run_on_tcp_with_options(
+        shim: B,
+        stream: TcpStream,
+        options: ServerOptions,
+    ) -> Result<(), B::Error> {
+        let peer_addr = stream.peer_addr().ok();
+        MysqlIntermediary::serve(shim, stream, options, peer_addr).await
     }
 }
 
+/// The `COM_SET_OPTION` options that turn multi-statement queries on and off.
+const MYSQL_OPTION_MULTI_STATEMENTS_ON: u16 = 0;
+const MYSQL_OPTION_MULTI_STATEMENTS_OFF: u16 = 1;
+
+/// The length of the scramble sent in the initial handshake.
+const SCRAMBLE_LENGTH: usize = 20;
+
+/// Generate the random challenge for a new connection.
+///
+/// The first `SCRAMBLE_LENGTH` bytes go in the initial handshake, while plugins that want a longer
+/// challenge (like MariaDB's `client_ed25519`) get all of it. Clients treat the scramble as a
+/// NUL-terminated string, so it is made up of printable characters only.
+fn generate_scramble() -> [u8; 32] {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut scramble = [0; 32];
+    for b in scramble.iter_mut() {
+        *b = rng.gen_range(b'!'..=b'~');
+    }
+    scramble
+}
+
 #[derive(Default)]
 struct StatementData {
     long_data: HashMap<u16, Vec<u8>>,
     bound_types: Vec<(myc::constants::ColumnType, bool)>,
     params: u16,
+    cursor: Option<resultset::Cursor>,
 }
 
 impl<B: MysqlShim> MysqlIntermediary<B> {
-    /// Create a new server over two one-way channels and process client commands until the client
-    /// disconnects or an error occurs.
-    pub async fn run_on(shim: B, writer: TcpStream) -> Result<(), B::Error> {
-        let w = packet::PacketWriter::new(writer);
+    /// Create a new server over a bidirectional stream and process client commands until the
+    /// client disconnects or an error occurs.
+    ///
+    /// Any stream implementing `AsyncRead + AsyncWrite` can be used, such as a Unix socket, a TLS
+    /// wrapper, or one end of a `tokio::io::duplex` pipe.
+    pub async fn run_on<S>(shim: B, stream: S) -> Result<(), B::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        MysqlIntermediary::run_with_options(shim, stream, ServerOptions::default()).await
+    }
+
+    /// Like [`run_on`](struct.MysqlIntermediary.html#method.run_on), but with the server
+    /// configured by the given [`ServerOptions`](struct.ServerOptions.html).
+    pub async fn run_with_options<S>(
+        shim: B,
+        stream: S,
+        options: ServerOptions,
+    ) -> Result<(), B::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        MysqlIntermediary::serve(shim, stream, options, None).await
+    }
+
+    async fn serve<S>(
+        shim: B,
+        stream: S,
+        options: ServerOptions,
+        peer_addr: Option<SocketAddr>,
+    ) -> Result<(), B::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut w = packet::PacketWriter::new(stream);
+        w.set_status_flags(options.initial_status_flags());
+        let registration = options.connection_registry.register(peer_addr);
         let mut mi = MysqlIntermediary {
             shim,
+            options,
+            context: ConnectionContext::new(
+                registration.id(),
+                peer_addr,
+                registration.cancellation(),
+            ),
+            registration,
+            connect_attrs: Vec::new(),
+            multi_statements: false,
+            warnings: Vec::new(),
+            scramble: generate_scramble(),
             reader: PacketBuff::new(),
             writer: w,
         };
-        mi.init().await?;
-        mi.run().await
+        if mi.init().await? {
+            mi.run().await
+        } else {
+            Ok(())
+        }
     }
 
-    async fn init(&mut self) -> Result<(), B::Error> {
+    async fn init(&mut self) -> Result<bool, B::Error> {
+        let capabilities = self.options.capabilities();
+
         self.writer.write_all(&[10])?; // protocol 10
 
-        // 5.1.10 because that's what Ruby's ActiveRecord requires
-        self.writer.write_all(&b"5.1.10-alpha-msql-proxy\0"[..])?;
+        self.writer
+            .write_all(self.options.server_version.as_bytes())?; // server version
+        self.writer.write_all(&[0x00])?;
 
-        self.writer.write_all(&[0x08, 0x00, 0x00, 0x00])?; // TODO: connection ID
-        self.writer.write_all(&b";X,po_k}\0"[..])?; // auth seed
-        self.writer.write_all(&[0x00, 0x42])?; // just 4.1 proto
-        self.writer.write_all(&[0x21])?; // UTF8_GENERAL_CI
-        self.writer.write_all(&[0x00, 0x00])?; // status flags
-        self.writer.write_all(&[0x00, 0x00])?; // extended capabilities
-        self.writer.write_all(&[0x00])?; // no plugins
+        self.writer
+            .write_u32::<LittleEndian>(self.context.connection_id)?;
+        self.writer.write_all(&self.scramble[..8])?; // auth seed
+        self.writer.write_all(&[0x00])?;
+        self.writer
+            .write_u16::<LittleEndian>(capabilities.bits() as u16)?; // capabilities
+        self.writer.write_u8(self.options.collation)?; // character set
+        self.writer
+            .write_u16::<LittleEndian>(self.writer.status().bits())?; // status flags
+        self.writer
+            .write_u16::<LittleEndian>((capabilities.bits() >> 16) as u16)?; // extended capabilities
+        self.writer.write_u8(SCRAMBLE_LENGTH as u8 + 1)?; // length of auth plugin data
         self.writer.write_all(&[0x00; 6][..])?; // filler
         self.writer.write_all(&[0x00; 4][..])?; // filler
-        self.writer.write_all(&b">o6^Wz!/kM}N\0"[..])?; // 4.1+ servers must extend salt
+        self.writer.write_all(&self.scramble[8..SCRAMBLE_LENGTH])?; // 4.1+ servers must extend salt
+        self.writer.write_all(&[0x00])?;
+        self.writer
+            .write_all(self.options.auth_plugin.name().as_bytes())?;
+        self.writer.write_all(&[0x00])?;
         self.writer.flush_all().await?;
 
-        {
-            let (seq, handshake) = self
-                .reader
-                .next(self.writer.get_stream())
-                .await?
-                .ok_or_else(|| {
-                    io::Error::new(
-                        io::ErrorKind::ConnectionAborted,
-                        "peer terminated connection",
-                    )
-                })?;
-
-            let _handshake = commands::client_handshake(&handshake)
-                .map_err(|e| match e {
-                    nom::Err::Incomplete(_) => io::Error::new(
-                        io::ErrorKind::UnexpectedEof,
-                        "client sent incomplete handshake",
-                    ),
-                    nom::Err::Failure((input, nom_e_kind))
-                    | nom::Err::Error((input, nom_e_kind)) => {
-                        if let nom::error::ErrorKind::Eof = nom_e_kind {
-                            io::Error::new(
-                                io::ErrorKind::UnexpectedEof,
-                                format!("client did not complete handshake; got {:?}", input),
-                            )
-                        } else {
-                            io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                format!("bad client handshake; got {:?} ({:?})", input, nom_e_kind),
-                            )
-                        }
+        let (seq, packet) = self.next_handshake_packet().await?;
+
+        #[cfg(feature = "tls")]
+        let (seq, packet, secure) = match self.options.tls {
+            Some(ref config) if commands::is_ssl_request(&packet) => {
+                tls::accept(config.clone(), &mut self.writer, &mut self.reader).await?;
+                let (seq, packet) = self.next_handshake_packet().await?;
+                (seq, packet, true)
+            }
+            _ => (seq, packet, false),
+        };
+        #[cfg(not(feature = "tls"))]
+        let secure = false;
+
+        let handshake = commands::client_handshake(&packet)
+            .map_err(|e| match e {
+                nom::Err::Incomplete(_) => io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "client sent incomplete handshake",
+                ),
+                nom::Err::Failure((input, nom_e_kind)) | nom::Err::Error((input, nom_e_kind)) => {
+                    if let nom::error::ErrorKind::Eof = nom_e_kind {
+                        io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("client did not complete handshake; got {:?}", input),
+                        )
+                    } else {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("bad client handshake; got {:?} ({:?})", input, nom_e_kind),
+                        )
                     }
-                })?
-                .1;
-            self.writer.set_seq(seq + 1);
+                }
+            })?
+            .1;
+        self.writer.set_seq(seq + 1);
+
+        #[cfg(feature = "tls")]
+        {
+            if self.options.require_secure_transport && !secure {
+                writers::write_err(
+                    ErrorKind::ER_SECURE_TRANSPORT_REQUIRED,
+                    b"Connections using insecure transport are prohibited while --require_secure_transport=ON.",
+                    &mut self.writer,
+                )?;
+                self.writer.flush_all().await?;
+                return Ok(false);
+            }
+        }
+
+        let username = ::std::str::from_utf8(handshake.username)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if !self
+            .authenticate(
+                username,
+                handshake.auth_plugin,
+                handshake.auth_response,
+                secure,
+            )
+            .await?
+        {
+            return Ok(false);
         }
 
+        self.log_in(username, handshake.database, &handshake.attributes);
+        self.context.capabilities = handshake.capabilities;
+        self.multi_statements = (capabilities & handshake.capabilities)
+            .contains(CapabilityFlags::CLIENT_MULTI_STATEMENTS);
+        self.writer.set_deprecate_eof(
+            (capabilities & handshake.capabilities).contains(CapabilityFlags::CLIENT_DEPRECATE_EOF),
+        );
+        self.writer.set_session_track(
+            (capabilities & handshake.capabilities).contains(CapabilityFlags::CLIENT_SESSION_TRACK),
+        );
+        self.context.collation = handshake.collation;
+        self.context.max_packet_size = handshake.maxps;
+        self.context.secure = secure;
+        self.registration.set_command("Sleep", None);
+        self.shim.on_connect(&self.context).await;
+
         writers::write_ok_packet(&mut self.writer, 0, 0, StatusFlags::empty())?;
 
         self.writer.flush_all().await?;
 
-        Ok(())
+        Ok(true)
+    }
+
+    /// Record that the client is now logged in as `username`, using `database`.
+    fn log_in(
+        &mut self,
+        username: &str,
+        database: Option<&[u8]>,
+        attributes: &[commands::Attribute<'_>],
+    ) {
+        self.context.username = username.to_owned();
+        self.context.database = database.map(|db| String::from_utf8_lossy(db).into_owned());
+        self.connect_attrs = attributes
+            .iter()
+            .map(|&(k, v)| {
+                (
+                    String::from_utf8_lossy(k).into_owned(),
+                    String::from_utf8_lossy(v).into_owned(),
+                )
+            })
+            .collect();
+        self.context.attributes = self.connect_attrs.iter().cloned().collect();
+        self.registration.set_user(username);
+        self.registration
+            .set_database(self.context.database.as_deref());
+    }
+
+    /// Log the client in again as a different user, on behalf of `COM_CHANGE_USER`.
+    ///
+    /// Returns `false` if the new user could not be authenticated, in which case the client has
+    /// been told, and the connection should be closed.
+    async fn change_user(
+        &mut self,
+        data: &[u8],
+        stmts: &mut HashMap<u32, StatementData>,
+    ) -> Result<bool, B::Error> {
+        let change = commands::change_user(data, self.context.capabilities)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad COM_CHANGE_USER"))?
+            .1;
+        let username = ::std::str::from_utf8(change.username)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // the session, including its statements, belonged to the previous user
+        self.reset_session(stmts).await;
+
+        if !self
+            .authenticate(
+                username,
+                change.auth_plugin,
+                change.auth_response,
+                self.context.secure,
+            )
+            .await?
+        {
+            return Ok(false);
+        }
+
+        self.log_in(username, change.database, &change.attributes);
+        if let Some(collation) = change.collation {
+            self.context.collation = collation;
+        }
+        self.shim.on_change_user(&self.context).await;
+
+        writers::write_ok_packet(&mut self.writer, 0, 0, StatusFlags::empty())?;
+        Ok(true)
+    }
+
+    /// Put the session back the way it was when the client connected, as `COM_RESET_CONNECTION`
+    /// and `COM_CHANGE_USER` do.
+    ///
+    /// All the statements the client has prepared are deallocated, as if it had closed each of
+    /// them, the warnings of the last statement are forgotten, and the status flags are restored.
+    async fn reset_session(&mut self, stmts: &mut HashMap<u32, StatementData>) {
+        for (stmt, _) in stmts.drain() {
+            self.shim.on_close(stmt).await;
+        }
+        self.warnings.clear();
+        self.writer
+            .set_status_flags(self.options.initial_status_flags());
+    }
+
+    /// Read the next packet from the client, or `None` if it has disconnected.
+    ///
+    /// The packet is copied out of the read buffer, so that `self` can be used freely while
+    /// handling it.
+    async fn next_packet(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        Ok(self
+            .reader
+            .next(self.writer.get_stream())
+            .await?
+            .map(|(seq, packet)| (seq, packet.to_vec())))
+    }
+
+    async fn next_handshake_packet(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        self.next_packet().await?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "peer terminated connection",
+            )
+        })
     }
 
     async fn run(mut self) -> Result<(), B::Error> {
         use crate::commands::Command;
 
+        let cancellation = self.context.cancellation.clone();
         let mut stmts: HashMap<u32, _> = HashMap::new();
-        while let Some((seq, packet)) = self.reader.next(self.writer.get_stream()).await? {
-            self.writer.set_seq(seq + 1);
-            let cmd = commands::parse(&packet).unwrap().1;
+        loop {
+            let (seq, packet) = match cancellation.run_until_killed(self.next_packet()).await {
+                Some(packet) => match packet? {
+                    Some(packet) => packet,
+                    None => break,
+                },
+                None => break,
+            };
+            // a KILL QUERY that arrived between commands has nothing left to interrupt
+            cancellation.reset();
+            self.writer.start_response(seq + 1);
+            let cmd = commands::parse(&packet)
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "client sent empty packet")
+                })?
+                .1;
+            self.registration
+                .set_command(cmd.name(), cmd.query().as_deref());
+            // like MySQL, only count the commands that do something for the client
+            let question = !matches!(
+                cmd,
+                Command::Ping
+                    | Command::Statistics
+                    | Command::Prepare(_)
+                    | Command::Close(_)
+                    | Command::ResetStmt(_)
+                    | Command::Quit
+            );
+            let started = Instant::now();
             match cmd {
                 Command::Query(q) => {
-                    if q.starts_with(b"SELECT @@") || q.starts_with(b"select @@") {
-                        let w = QueryResultWriter::new(&mut self.writer, false);
-                        let var = &q[b"SELECT @@".len()..];
-                        match var {
-                            b"max_allowed_packet" => {
-                                let cols = &[Column {
-                                    table: String::new(),
-                                    column: "@@max_allowed_packet".to_owned(),
-                                    coltype: myc::constants::ColumnType::MYSQL_TYPE_LONG,
-                                    colflags: myc::constants::ColumnFlags::UNSIGNED_FLAG,
-                                }];
-                                let mut writer = w.start(cols).await?;
-                                writer.write_row(iter::once(67108864u32))?;
-                                writer.finish().await?;
-                            }
-                            _ => {
-                                w.completed(0, 0).await?;
-                            }
-                        }
-                    } else if q.starts_with(b"USE ") || q.starts_with(b"use ") {
-                        let w = InitWriter {
-                            writer: &mut self.writer,
-                        };
-                        let schema = ::std::str::from_utf8(&q[b"USE ".len()..])
-                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-                        let schema = schema.trim().trim_end_matches(';').trim_matches('`');
-                        self.shim.on_init(&schema, w).await?;
+                    let statements = if self.multi_statements {
+                        split::statements(q)
                     } else {
-                        let w = QueryResultWriter::new(&mut self.writer, false);
-                        self.shim
-                            .on_query(
-                                ::std::str::from_utf8(q)
-                                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
-                                w,
-                            )
-                            .await?;
+                        Vec::new()
+                    };
+                    if statements.len() < 2 {
+                        if !self.query(q).await? {
+                            break;
+                        }
+                    } else if !self.multi_query(statements).await? {
+                        break;
                     }
                 }
-                Command::Prepare(q) => {
-                    let w = StatementMetaWriter {
-                        writer: &mut self.writer,
-                        stmts: &mut stmts,
-                    };
+                Command::Prepare(q) => match ::std::str::from_utf8(q) {
+                    Ok(q) => {
+                        let w = StatementMetaWriter {
+                            writer: &mut self.writer,
+                            stmts: &mut stmts,
+                        };
 
-                    self.shim
-                        .on_prepare(
-                            ::std::str::from_utf8(q)
-                                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
-                            w,
-                        )
-                        .await?;
-                }
-                Command::Execute { stmt, params } => {
-                    let state = stmts.get_mut(&stmt).ok_or_else(|| {
-                        io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            format!("asked to execute unknown statement {}", stmt),
-                        )
-                    })?;
-                    {
-                        let params = params::ParamParser::new(params, state);
-                        let w = QueryResultWriter::new(&mut self.writer, true);
-                        self.shim.on_execute(stmt, params, w).await?;
+                        self.shim
+                            .on_prepare(q, w)
+                            .await
+                            .or_else(|e| self.report(e))?;
+                        self.warnings = self.writer.take_warnings();
                     }
-                    state.long_data.clear();
-                }
+                    Err(_) => self.invalid_utf8(q)?,
+                },
+                Command::Execute {
+                    stmt,
+                    cursor,
+                    params,
+                } => match stmts.get_mut(&stmt) {
+                    Some(state) => match params::ParamParser::new(params, state) {
+                        Ok(params) => {
+                            // executing the statement again closes the cursor it may have open
+                            let mut new_cursor = None;
+                            let mut w = QueryResultWriter::new(&mut self.writer, true);
+                            if cursor {
+                                w = w.with_cursor(&mut new_cursor);
+                            }
+                            let result = cancellation
+                                .run(self.shim.on_execute(stmt, params, w))
+                                .await;
+                            state.long_data.clear();
+                            state.cursor = new_cursor;
+                            match result {
+                                Some(result) => result.or_else(|e| self.report(e))?,
+                                None if self.interrupted()? => {}
+                                None => break,
+                            }
+                            self.warnings = self.writer.take_warnings();
+                        }
+                        Err(_) => writers::write_err(
+                            ErrorKind::ER_WRONG_ARGUMENTS,
+                            b"Incorrect arguments to mysqld_stmt_execute",
+                            &mut self.writer,
+                        )?,
+                    },
+                    None => self.unknown_statement(stmt, "mysqld_stmt_execute")?,
+                },
                 Command::SendLongData { stmt, param, data } => {
-                    stmts
-                        .get_mut(&stmt)
-                        .ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                format!("got long data packet for unknown statement {}", stmt),
-                            )
-                        })?
-                        .long_data
-                        .entry(param)
-                        .or_insert_with(Vec::new)
-                        .extend(data);
+                    // there is no response to tell the client about an unknown statement with, and
+                    // executing that statement will fail anyway
+                    if let Some(state) = stmts.get_mut(&stmt) {
+                        state
+                            .long_data
+                            .entry(param)
+                            .or_insert_with(Vec::new)
+                            .extend(data);
+                    }
                 }
                 Command::Close(stmt) => {
                     self.shim.on_close(stmt).await;
                     stmts.remove(&stmt);
                     // NOTE: spec dictates no response from server
                 }
-                Command::ListFields(_) => {
+                Command::ListFields(data) => {
+                    let (table, wildcard) = commands::list_fields(data);
+                    match (
+                        ::std::str::from_utf8(table),
+                        ::std::str::from_utf8(wildcard),
+                    ) {
+                        (Ok(table), Ok(wildcard)) => {
+                            let w = ListFieldsWriter {
+                                writer: &mut self.writer,
+                            };
+                            self.shim
+                                .on_list_fields(table, wildcard, w)
+                                .await
+                                .or_else(|e| self.report(e))?;
+                            self.warnings = self.writer.take_warnings();
+                        }
+                        (Err(_), _) => self.invalid_utf8(table)?,
+                        (_, Err(_)) => self.invalid_utf8(wildcard)?,
+                    }
+                }
+                Command::Init(schema) => match ::std::str::from_utf8(schema) {
+                    Ok(schema) => {
+                        self.init_db(schema).await?;
+                        self.warnings = self.writer.take_warnings();
+                    }
+                    Err(_) => self.invalid_utf8(schema)?,
+                },
+                Command::ChangeUser(data) => {
+                    if !self.change_user(data, &mut stmts).await? {
+                        break;
+                    }
+                }
+                Command::Fetch { stmt, rows } => {
+                    match stmts.get_mut(&stmt).map(|state| &mut state.cursor) {
+                        None => self.unknown_statement(stmt, "mysqld_stmt_fetch")?,
+                        Some(None) => writers::write_err(
+                            ErrorKind::ER_STMT_HAS_NO_OPEN_CURSOR,
+                            format!("The statement ({}) has no open cursor.", stmt).as_bytes(),
+                            &mut self.writer,
+                        )?,
+                        Some(Some(cursor)) => {
+                            let result =
+                                cancellation.run(cursor.fetch(rows, &mut self.writer)).await;
+                            // the cursor is closed once it runs out of rows, fails, or is killed
+                            if !matches!(result, Some(Ok(false))) {
+                                stmts.get_mut(&stmt).unwrap().cursor = None;
+                            }
+                            match result {
+                                Some(Ok(_)) => {}
+                                Some(Err(e)) => self.report(e.into())?,
+                                None if self.interrupted()? => {}
+                                None => break,
+                            }
+                        }
+                    }
+                }
+                Command::ResetStmt(stmt) => match stmts.get_mut(&stmt) {
+                    Some(state) => {
+                        // like MySQL, keep the types bound by the last execution, which clients do
+                        // not send again
+                        state.long_data.clear();
+                        state.cursor = None;
+                        self.shim.on_reset_stmt(stmt).await;
+                        writers::write_ok_packet(&mut self.writer, 0, 0, StatusFlags::empty())?;
+                    }
+                    None => self.unknown_statement(stmt, "mysqld_stmt_reset")?,
+                },
+                Command::SetOption(option) => match option {
+                    MYSQL_OPTION_MULTI_STATEMENTS_ON | MYSQL_OPTION_MULTI_STATEMENTS_OFF => {
+                        self.multi_statements = option == MYSQL_OPTION_MULTI_STATEMENTS_ON;
+                        writers::write_eof_packet(&mut self.writer, StatusFlags::empty())?;
+                    }
+                    _ => writers::write_err(
+                        ErrorKind::ER_UNKNOWN_COM_ERROR,
+                        b"Unknown command",
+                        &mut self.writer,
+                    )?,
+                },
+                Command::ResetConnection => {
+                    self.reset_session(&mut stmts).await;
+                    self.shim.on_reset().await;
+                    writers::write_ok_packet(&mut self.writer, 0, 0, StatusFlags::empty())?;
+                }
+                Command::ProcessKill(id) => {
+                    self.kill(id, true)?;
+                }
+                Command::Statistics => {
+                    let statistics = self.options.connection_registry.statistics();
+                    let mut reply = self.shim.on_statistics(statistics).await;
+                    if reply.is_empty() {
+                        // an empty reply would be no packet at all, which the client would wait for
+                        reply = statistics.to_string();
+                    }
+                    // the reply is the bare string, rather than an OK packet
+                    self.writer.write_all(reply.as_bytes())?;
+                    self.writer.end_packet();
+                }
+                Command::Ping | Command::Debug => {
+                    writers::write_ok_packet(&mut self.writer, 0, 0, StatusFlags::empty())?;
+                }
+                Command::Quit => {
+                    break;
+                }
+                Command::Malformed(command) => {
+                    // the client is not waiting for a response to some commands, and would take an
+                    // error for the response to its next one
+                    if commands::expects_response(command) {
+                        writers::write_err(
+                            ErrorKind::ER_MALFORMED_PACKET,
+                            b"Malformed communication packet.",
+                            &mut self.writer,
+                        )?;
+                    }
+                }
+                Command::Unknown(command, data) => {
+                    let w = QueryResultWriter::new(&mut self.writer, false);
+                    self.shim
+                        .on_unknown_command(command, data, w)
+                        .await
+                        .or_else(|e| self.report(e))?;
+                }
+            }
+            // an error sent in response to any other command is listed by the next SHOW WARNINGS
+            if self.writer.warning_count() > 0 {
+                self.warnings = self.writer.take_warnings();
+            }
+            self.writer.flush_all().await?;
+            if question {
+                self.options
+                    .connection_registry
+                    .record_question(started.elapsed());
+            }
+            self.registration.set_command("Sleep", None);
+            if cancellation.is_killed() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the client that a statement it referred to in a call to `function` was never
+    /// prepared.
+    fn unknown_statement(&mut self, stmt: u32, function: &str) -> io::Result<()> {
+        writers::write_err(
+            ErrorKind::ER_UNKNOWN_STMT_HANDLER,
+            format!(
+                "Unknown prepared statement handler ({}) given to {}",
+                stmt, function
+            )
+            .as_bytes(),
+            &mut self.writer,
+        )
+    }
+
+    /// Tell the client that the text it sent, `s`, is not valid UTF-8.
+    fn invalid_utf8(&mut self, s: &[u8]) -> io::Result<()> {
+        // like MySQL, only quote the start of it
+        let s = String::from_utf8_lossy(&s[..s.len().min(64)]);
+        writers::write_err(
+            ErrorKind::ER_INVALID_CHARACTER_STRING,
+            format!("Invalid utf8 character string: '{}'", s).as_bytes(),
+            &mut self.writer,
+        )
+    }
+
+    /// Tell the client that the shim failed to handle the command it sent.
+    ///
+    /// The error is returned instead, closing the connection, if it is fatal or if part of the
+    /// response had already been sent.
+    fn report(&mut self, e: B::Error) -> Result<(), B::Error> {
+        if !self.writer.reset_response() {
+            return Err(e);
+        }
+        let (kind, message) = e.into_mysql_error()?;
+        writers::write_err(kind, message.as_bytes(), &mut self.writer)?;
+        Ok(())
+    }
+
+    /// Tell the client that the command it sent was interrupted by `KILL`.
+    ///
+    /// Returns `false` if part of the response had already been sent, in which case the client
+    /// cannot be told, and the connection has to be closed instead.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if !self.writer.reset_response() {
+            return Ok(false);
+        }
+        writers::write_err(
+            ErrorKind::ER_QUERY_INTERRUPTED,
+            b"Query execution was interrupted",
+            &mut self.writer,
+        )?;
+        Ok(true)
+    }
+
+    /// Run the query `q`, returning `false` if the connection has to be closed.
+    async fn query(&mut self, q: &[u8]) -> Result<bool, B::Error> {
+        if let Some(builtin) = builtins::Builtin::parse(q) {
+            let diagnostic = builtin.is_diagnostic();
+            self.run_builtin(builtin).await?;
+            if diagnostic {
+                // the warnings reported on are those of the statement before, which stay around
+                return Ok(true);
+            }
+        } else if q.starts_with(b"SELECT @@") || q.starts_with(b"select @@") {
+            let w = QueryResultWriter::new(&mut self.writer, false);
+            let var = &q[b"SELECT @@".len()..];
+            match var {
+                b"max_allowed_packet" => {
                     let cols = &[Column {
                         table: String::new(),
-                        column: "not implemented".to_owned(),
-                        coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
+                        column: "@@max_allowed_packet".to_owned(),
+                        coltype: myc::constants::ColumnType::MYSQL_TYPE_LONG,
                         colflags: myc::constants::ColumnFlags::UNSIGNED_FLAG,
+                        ..Default::default()
                     }];
-                    writers::write_column_definitions(cols, &mut self.writer, true)?;
+                    let mut writer = w.start(cols).await?;
+                    writer.write_row(iter::once(67108864u32))?;
+                    writer.finish().await?;
                 }
-                Command::Init(schema) => {
-                    let w = InitWriter {
-                        writer: &mut self.writer,
-                    };
-                    self.shim
-                        .on_init(
-                            ::std::str::from_utf8(schema)
-                                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
-                            w,
-                        )
-                        .await?;
+                _ => {
+                    w.completed(0, 0).await?;
                 }
-                Command::Ping => {
-                    writers::write_ok_packet(&mut self.writer, 0, 0, StatusFlags::empty())?;
+            }
+        } else if q.starts_with(b"USE ") || q.starts_with(b"use ") {
+            let schema = &q[b"USE ".len()..];
+            match ::std::str::from_utf8(schema) {
+                Ok(schema) => {
+                    let schema = schema.trim().trim_end_matches(';').trim_matches('`');
+                    self.init_db(schema).await?;
                 }
-                Command::Quit => {
-                    break;
+                Err(_) => self.invalid_utf8(schema)?,
+            }
+        } else if let Ok(q) = ::std::str::from_utf8(q) {
+            let status = self.writer.status_flags();
+            if self.options.track_transactions {
+                if let Some(change) = transaction::TransactionChange::parse(q.as_bytes()) {
+                    self.writer.set_status_flags(change.apply(status));
+                    if let transaction::TransactionChange::Autocommit(on) = change {
+                        self.writer
+                            .add_session_state(&SessionStateChange::SystemVariable {
+                                name: "autocommit".to_owned(),
+                                value: if on { "ON" } else { "OFF" }.to_owned(),
+                            })?;
+                    }
                 }
             }
+            let w = QueryResultWriter::new(&mut self.writer, false);
+            match self
+                .context
+                .cancellation
+                .run(self.shim.on_query(q, w))
+                .await
+            {
+                Some(result) => result.or_else(|e| self.report(e))?,
+                None if self.interrupted()? => {}
+                None => return Ok(false),
+            }
+            if self.writer.failed() {
+                self.writer.set_status_flags(status);
+            }
+        } else {
+            self.invalid_utf8(q)?;
+        }
+        self.warnings = self.writer.take_warnings();
+        Ok(true)
+    }
+
+    /// Run the statements of a multi-statement query one after the other, until one of them fails.
+    ///
+    /// Returns `false` if the connection has to be closed.
+    async fn multi_query(&mut self, statements: Vec<&[u8]>) -> Result<bool, B::Error> {
+        let last = statements.len() - 1;
+        for (i, statement) in statements.into_iter().enumerate() {
+            // whatever happens to this statement, the results of the previous ones stand
             self.writer.flush_all().await?;
+            self.writer.start_statement(i != last);
+            let keep_going = self.query(statement).await?;
+            if !keep_going || self.writer.failed() {
+                self.writer.start_statement(false);
+                return Ok(keep_going);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Ask the shim to switch to `schema`, and keep track of the current database if it agrees.
+    async fn init_db(&mut self, schema: &str) -> Result<(), B::Error> {
+        let mut changed = false;
+        let w = InitWriter {
+            writer: &mut self.writer,
+            schema,
+            changed: &mut changed,
+        };
+        if let Err(e) = self.shim.on_init(schema, w).await {
+            return self.report(e);
+        }
+        if changed {
+            self.registration.set_database(Some(schema));
+            self.context.database = Some(schema.to_owned());
         }
         Ok(())
     }
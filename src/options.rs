@@ -0,0 +1,221 @@
+use crate::auth::RsaKey;
+use crate::myc::constants::{CapabilityFlags, StatusFlags, UTF8_GENERAL_CI};
+use crate::{AuthPlugin, Authenticator, CachingSha2PasswordCache, ConnectionRegistry};
+use std::sync::{Arc, OnceLock};
+
+/// Settings that control how a [`MysqlIntermediary`](struct.MysqlIntermediary.html) presents
+/// itself to clients.
+///
+/// A single `ServerOptions` is meant to be shared by all the connections a process serves, and
+/// cloning it is cheap.
+#[derive(Clone)]
+pub struct ServerOptions {
+    pub(crate) server_version: String,
+    pub(crate) collation: u8,
+    pub(crate) capabilities: CapabilityFlags,
+    pub(crate) status_flags: StatusFlags,
+    pub(crate) track_transactions: bool,
+    pub(crate) authenticator: Option<Arc<dyn Authenticator>>,
+    pub(crate) auth_plugin: AuthPlugin,
+    pub(crate) caching_sha2_password_cache: Arc<CachingSha2PasswordCache>,
+    pub(crate) rsa_key: Option<Arc<RsaKey>>,
+    pub(crate) connection_registry: Arc<ConnectionRegistry>,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<Arc<crate::rustls::ServerConfig>>,
+    #[cfg(feature = "tls")]
+    pub(crate) require_secure_transport: bool,
+}
+
+/// The registry shared by all options that were not given one of their own, so that every
+/// connection in the process can see (and kill) every other.
+fn default_connection_registry() -> Arc<ConnectionRegistry> {
+    static REGISTRY: OnceLock<Arc<ConnectionRegistry>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default).clone()
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            // 5.1.10 because that's what Ruby's ActiveRecord requires
+            server_version: "5.1.10-alpha-msql-proxy".to_owned(),
+            collation: UTF8_GENERAL_CI as u8,
+            capabilities: CapabilityFlags::CLIENT_PROTOCOL_41
+                | CapabilityFlags::CLIENT_RESERVED
+                | CapabilityFlags::CLIENT_SECURE_CONNECTION
+                | CapabilityFlags::CLIENT_PLUGIN_AUTH
+                | CapabilityFlags::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA
+                | CapabilityFlags::CLIENT_CONNECT_ATTRS
+                | CapabilityFlags::CLIENT_CONNECT_WITH_DB
+                | CapabilityFlags::CLIENT_DEPRECATE_EOF
+                | CapabilityFlags::CLIENT_SESSION_TRACK,
+            status_flags: StatusFlags::empty(),
+            track_transactions: false,
+            authenticator: None,
+            auth_plugin: AuthPlugin::default(),
+            caching_sha2_password_cache: Default::default(),
+            rsa_key: None,
+            connection_registry: default_connection_registry(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            require_secure_transport: false,
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Report `version` as the server version in the initial handshake.
+    ///
+    /// Clients commonly enable or disable features based on this. To be recognized as MariaDB,
+    /// use a version with the `5.5.5-` prefix MariaDB itself sends, such as
+    /// `5.5.5-10.6.12-MariaDB`.
+    ///
+    /// The handshake ends the version with a NUL byte, so any NUL bytes in `version` are removed.
+    pub fn with_server_version<S: Into<String>>(mut self, version: S) -> Self {
+        let mut version = version.into();
+        version.retain(|c| c != '\0');
+        self.server_version = version;
+        self
+    }
+
+    /// Tell clients that the server's default character set and collation is `collation`.
+    ///
+    /// The default is `utf8_general_ci` (33).
+    pub fn with_collation(mut self, collation: u8) -> Self {
+        self.collation = collation;
+        self
+    }
+
+    /// Advertise `capabilities` to clients in the initial handshake.
+    ///
+    /// `CLIENT_SSL` is added whenever TLS is configured. Advertising a capability does not
+    /// teach the server to implement it, so this is mostly useful for hiding capabilities from
+    /// clients that misbehave with them.
+    pub fn with_capabilities(mut self, capabilities: CapabilityFlags) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Let clients send several statements in one query, separated by `;`.
+    ///
+    /// This advertises `CLIENT_MULTI_STATEMENTS`. Queries from clients that ask for it (or that
+    /// turn it on later with `COM_SET_OPTION`) are split into their statements, which are given
+    /// to the shim one at a time, and whose results are sent back one after the other. Without
+    /// it, such a query is given to the shim as a whole.
+    pub fn with_multi_statements(mut self, enable: bool) -> Self {
+        self.capabilities
+            .set(CapabilityFlags::CLIENT_MULTI_STATEMENTS, enable);
+        self
+    }
+
+    /// Send `status_flags` as the server status in the initial handshake.
+    ///
+    /// These are also the status flags that connections start with, and go back to when they are
+    /// reset, until the shim changes them through one of its writers.
+    pub fn with_status_flags(mut self, status_flags: StatusFlags) -> Self {
+        self.status_flags = status_flags;
+        self
+    }
+
+    /// Keep the `SERVER_STATUS_IN_TRANS` and `SERVER_STATUS_AUTOCOMMIT` status flags up to date
+    /// as clients run `BEGIN`, `START TRANSACTION`, `COMMIT`, `ROLLBACK` and
+    /// `SET autocommit = ...` queries.
+    ///
+    /// The flags are changed before such a query is given to the shim, so that its response
+    /// carries them, and changed back if the shim fails it. Enabling this also starts connections
+    /// in autocommit mode, like MySQL does.
+    pub fn with_transaction_tracking(mut self, enable: bool) -> Self {
+        self.track_transactions = enable;
+        self
+    }
+
+    /// Check the credentials of connecting clients with `authenticator`.
+    ///
+    /// Without an authenticator, any user is let in regardless of the password they give.
+    pub fn with_authenticator<A: Authenticator + 'static>(mut self, authenticator: A) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Ask clients to authenticate with `plugin`.
+    ///
+    /// The default is [`AuthPlugin::MysqlNativePassword`](enum.AuthPlugin.html).
+    pub fn with_auth_plugin(mut self, plugin: AuthPlugin) -> Self {
+        self.auth_plugin = plugin;
+        self
+    }
+
+    /// Let clients on unencrypted connections send their password encrypted with `key` when
+    /// `caching_sha2_password` needs it in full.
+    ///
+    /// Without a key, such clients can only log in through the fast path, or over TLS.
+    pub fn with_rsa_key(mut self, key: crate::rsa::RsaPrivateKey) -> Self {
+        self.rsa_key = Some(Arc::new(RsaKey::new(key)));
+        self
+    }
+
+    /// The cache `caching_sha2_password` uses to let returning users log in without sending
+    /// their full password. It is shared by all clones of these options.
+    pub fn caching_sha2_password_cache(&self) -> Arc<CachingSha2PasswordCache> {
+        self.caching_sha2_password_cache.clone()
+    }
+
+    /// Keep track of the connections served with these options in `registry`.
+    ///
+    /// By default, all connections in the process share a single registry, so that
+    /// `SHOW PROCESSLIST` and `KILL` reach every one of them. Servers that should not see each
+    /// other's connections can each be given their own.
+    pub fn with_connection_registry(mut self, registry: Arc<ConnectionRegistry>) -> Self {
+        self.connection_registry = registry;
+        self
+    }
+
+    /// The status flags connections start with, and go back to when they are reset.
+    pub(crate) fn initial_status_flags(&self) -> StatusFlags {
+        if self.track_transactions {
+            self.status_flags | StatusFlags::SERVER_STATUS_AUTOCOMMIT
+        } else {
+            self.status_flags
+        }
+    }
+
+    /// The registry of the live connections served with these options. It is shared by all
+    /// clones of these options.
+    pub fn connection_registry(&self) -> Arc<ConnectionRegistry> {
+        self.connection_registry.clone()
+    }
+
+    /// Offer TLS to clients, using `config` for the handshake.
+    ///
+    /// The server will advertise `CLIENT_SSL`, and clients that send an `SSLRequest` have their
+    /// connection upgraded before they send their credentials.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<crate::rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Reject clients that do not upgrade their connection to TLS.
+    ///
+    /// Such clients receive an `ER_SECURE_TRANSPORT_REQUIRED` error in response to their
+    /// handshake, and the connection is closed.
+    #[cfg(feature = "tls")]
+    pub fn require_secure_transport(mut self, require: bool) -> Self {
+        self.require_secure_transport = require;
+        self
+    }
+}
+
+impl ServerOptions {
+    /// The capabilities advertised to clients in the initial handshake.
+    pub(crate) fn capabilities(&self) -> CapabilityFlags {
+        let capabilities = self.capabilities;
+        #[cfg(feature = "tls")]
+        let capabilities = if self.tls.is_some() {
+            capabilities | CapabilityFlags::CLIENT_SSL
+        } else {
+            capabilities
+        };
+        capabilities
+    }
+}
@@ -1,16 +1,44 @@
+use crate::myc::constants::StatusFlags;
+use crate::session::SessionStateChange;
+use crate::Warning;
 use byteorder::{ByteOrder, LittleEndian};
 use std::io;
 use std::io::prelude::Write;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const U24_MAX: usize = 16_777_215;
 
+/// A bidirectional byte stream that the protocol can be spoken over.
+///
+/// The writers handed to `MysqlShim` only ever see a `PacketWriter`, so the concrete stream type
+/// is erased here rather than threaded through every public type.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
 pub struct PacketWriter {
     to_write: Vec<u8>,
     seq: u8,
-    w: TcpStream,
+    w: Box<dyn Stream>,
     last_packet_start: usize,
+    // where the response to the current command starts, and whether any of it has been sent
+    response_seq: u8,
+    sent: bool,
+    // whether an ERR packet has been written in response to the current command
+    failed: bool,
+    // the status flags of the session, which persist across commands
+    status_flags: StatusFlags,
+    // set while a statement of a multi-statement query other than the last one is being run
+    more_results: bool,
+    // whether the client and server agreed on CLIENT_DEPRECATE_EOF
+    deprecate_eof: bool,
+    // whether the client and server agreed on CLIENT_SESSION_TRACK, and the encoded session
+    // state changes to send with the next OK packet
+    session_track: bool,
+    session_state: Vec<u8>,
+    // the warnings raised by the current statement, and the info string for its OK packet
+    warnings: Vec<Warning>,
+    info: Vec<u8>,
 }
 
 impl Write for PacketWriter {
@@ -32,8 +60,21 @@ impl Write for PacketWriter {
 }
 
 impl PacketWriter {
-    pub fn get_stream(&mut self) -> &mut TcpStream {
-        &mut self.w
+    pub fn get_stream(&mut self) -> &mut dyn Stream {
+        &mut *self.w
+    }
+
+    /// Take the underlying stream out of this writer, e.g. to wrap it in TLS.
+    ///
+    /// The writer must be given a stream again with `set_stream` before it is used.
+    #[cfg(feature = "tls")]
+    pub fn take_stream(&mut self) -> Box<dyn Stream> {
+        std::mem::replace(&mut self.w, Box::new(io::Cursor::new(Vec::new())))
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn set_stream(&mut self, w: Box<dyn Stream>) {
+        self.w = w;
     }
 
     pub async fn flush_all(&mut self) -> io::Result<()> {
@@ -45,16 +86,27 @@ impl PacketWriter {
             self.w.flush().await?;
             self.to_write.truncate(4);
             self.last_packet_start = 0;
+            self.sent = true;
         }
         Ok(())
     }
 
-    pub fn new(w: TcpStream) -> Self {
+    pub fn new<S: Stream + 'static>(w: S) -> Self {
         PacketWriter {
             to_write: vec![0, 0, 0, 0],
             seq: 0,
             last_packet_start: 0,
-            w,
+            w: Box::new(w),
+            response_seq: 0,
+            sent: false,
+            failed: false,
+            status_flags: StatusFlags::empty(),
+            more_results: false,
+            deprecate_eof: false,
+            session_track: false,
+            session_state: Vec::new(),
+            warnings: Vec::new(),
+            info: Vec::new(),
         }
     }
 
@@ -79,6 +131,144 @@ impl PacketWriter {
     pub fn set_seq(&mut self, seq: u8) {
         self.seq = seq;
     }
+
+    /// Start the response to a new command, whose first packet has sequence number `seq`.
+    pub fn start_response(&mut self, seq: u8) {
+        self.set_seq(seq);
+        self.response_seq = seq;
+        self.sent = false;
+        self.failed = false;
+        self.session_state.clear();
+        self.warnings.clear();
+        self.info.clear();
+    }
+
+    /// Start the response to the next statement of a multi-statement query, once the responses
+    /// to the previous ones have been flushed, so that it can be reset on its own.
+    ///
+    /// `more_results` says whether other statements follow this one, which is announced to the
+    /// client in every OK and EOF packet of its response.
+    pub fn start_statement(&mut self, more_results: bool) {
+        self.response_seq = self.seq;
+        self.sent = false;
+        self.more_results = more_results;
+        self.warnings.clear();
+        self.info.clear();
+    }
+
+    /// The status flags that the OK and EOF packets of the current response must carry.
+    pub fn status(&self) -> StatusFlags {
+        let mut status = self.status_flags;
+        status.set(StatusFlags::SERVER_MORE_RESULTS_EXISTS, self.more_results);
+        status
+    }
+
+    /// The status flags of the session, such as whether it is in a transaction.
+    pub fn status_flags(&self) -> StatusFlags {
+        self.status_flags
+    }
+
+    /// Change the status flags of the session, which are sent with every OK and EOF packet from
+    /// now on.
+    pub fn set_status_flags(&mut self, status_flags: StatusFlags) {
+        self.status_flags = status_flags;
+    }
+
+    /// Turn the given status flags of the session on or off.
+    pub fn toggle_status_flags(&mut self, flags: StatusFlags, enabled: bool) {
+        self.status_flags.set(flags, enabled);
+    }
+
+    /// Replace EOF packets with OK packets, as clients that negotiated `CLIENT_DEPRECATE_EOF`
+    /// expect.
+    pub fn set_deprecate_eof(&mut self, deprecate_eof: bool) {
+        self.deprecate_eof = deprecate_eof;
+    }
+
+    /// Whether EOF packets are replaced with OK packets.
+    pub fn deprecate_eof(&self) -> bool {
+        self.deprecate_eof
+    }
+
+    /// Tell the client about changes to its session, as clients that negotiated
+    /// `CLIENT_SESSION_TRACK` expect.
+    pub fn set_session_track(&mut self, session_track: bool) {
+        self.session_track = session_track;
+    }
+
+    /// Report `change` to the client in the next OK packet, if it tracks its session state.
+    pub fn add_session_state(&mut self, change: &SessionStateChange) -> io::Result<()> {
+        if self.session_track {
+            change.write(&mut self.session_state)?;
+        }
+        Ok(())
+    }
+
+    /// Take the encoded session state changes that the next OK packet must carry.
+    pub fn take_session_state(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.session_state)
+    }
+
+    /// Whether the client tracks its session state, and so expects the info string of OK packets
+    /// to be length-encoded.
+    pub fn session_track(&self) -> bool {
+        self.session_track
+    }
+
+    /// Record a warning raised by the current statement.
+    pub fn add_warning(&mut self, warning: Warning) {
+        self.warnings.push(warning);
+    }
+
+    /// The number of warnings the current statement raised, as reported in OK and EOF packets.
+    pub fn warning_count(&self) -> u16 {
+        self.warnings.len().min(usize::from(u16::MAX)) as u16
+    }
+
+    /// Take the warnings the current statement raised.
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Send `info` as the human-readable info string of the next OK packet.
+    pub fn set_info(&mut self, info: &[u8]) {
+        self.info.clear();
+        self.info.extend(info);
+    }
+
+    /// Take the info string that the next OK packet must carry.
+    pub fn take_info(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.info)
+    }
+
+    /// Record that the response to the current command is an error.
+    pub fn set_failed(&mut self) {
+        self.failed = true;
+    }
+
+    /// Whether an error has been written in response to the current command.
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Throw away what has been written in response to the current command, so that a different
+    /// response can be given instead.
+    ///
+    /// Returns `false`, and leaves the writer untouched, if part of the response has already been
+    /// sent to the client.
+    pub fn reset_response(&mut self) -> bool {
+        if self.sent {
+            return false;
+        }
+        self.to_write.truncate(4);
+        self.last_packet_start = 0;
+        self.seq = self.response_seq;
+        self.failed = false;
+        self.session_state.clear();
+        self.warnings.clear();
+        self.info.clear();
+        true
+    }
 }
 
 pub struct PacketBuff {
@@ -98,7 +288,20 @@ impl PacketBuff {
 }
 
 impl PacketBuff {
-    pub async fn next(&mut self, reader: &mut TcpStream) -> io::Result<Option<(u8, Packet<'_>)>> {
+    /// Take any bytes that have been read from the stream but not yet parsed into a packet.
+    #[cfg(feature = "tls")]
+    pub fn take_unread(&mut self) -> Vec<u8> {
+        let rest = self.bytes.split_off(self.bytes.len() - self.remaining);
+        self.bytes.clear();
+        self.start = 0;
+        self.remaining = 0;
+        rest
+    }
+
+    pub async fn next<R>(&mut self, reader: &mut R) -> io::Result<Option<(u8, Packet<'_>)>>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
         self.start = self.bytes.len() - self.remaining;
 
         loop {
@@ -1,6 +1,7 @@
 use crate::myc;
 use crate::{StatementData, Value};
 use std::collections::HashMap;
+use std::io;
 
 /// A `ParamParser` decodes query parameters included in a client's `EXECUTE` command given
 /// type information for the expected parameters.
@@ -15,13 +16,23 @@ pub struct ParamParser<'a> {
 }
 
 impl<'a> ParamParser<'a> {
-    pub(crate) fn new(input: &'a [u8], stmt: &'a mut StatementData) -> Self {
-        ParamParser {
+    /// Fails if the client sent no types for the parameters, and never sent them for an earlier
+    /// execution of the statement either.
+    pub(crate) fn new(input: &'a [u8], stmt: &'a mut StatementData) -> io::Result<Self> {
+        let nullmap_len = (stmt.params as usize).div_ceil(8);
+        let types_sent = input.get(nullmap_len).is_some_and(|&bound| bound != 0x00);
+        if !types_sent && stmt.bound_types.len() < stmt.params as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "parameter types were never sent",
+            ));
+        }
+        Ok(ParamParser {
             params: stmt.params,
             bytes: input,
             long_data: &stmt.long_data,
             bound_types: &mut stmt.bound_types,
-        }
+        })
     }
 }
 
@@ -67,23 +78,28 @@ impl<'a> Iterator for Params<'a> {
             self.nullmap = Some(nullmap);
             self.input = rest;
 
-            if !rest.is_empty() && rest[0] != 0x00 {
-                let (typmap, rest) = rest[1..].split_at(2 * self.params as usize);
-                self.bound_types.clear();
-                for i in 0..self.params as usize {
-                    self.bound_types.push((
-                        myc::constants::ColumnType::from(typmap[2 * i as usize]),
-                        (typmap[2 * i as usize + 1] & 128) != 0,
-                    ));
-                }
+            // the new-params-bound flag, followed by the types if it is set
+            if let Some((&bound, rest)) = rest.split_first() {
                 self.input = rest;
+                if bound != 0x00 {
+                    let (typmap, rest) = rest.split_at(2 * self.params as usize);
+                    self.bound_types.clear();
+                    for i in 0..self.params as usize {
+                        self.bound_types.push((
+                            myc::constants::ColumnType::from(typmap[2 * i as usize]),
+                            (typmap[2 * i as usize + 1] & 128) != 0,
+                        ));
+                    }
+                    self.input = rest;
+                }
             }
         }
 
         if self.col >= self.params {
             return None;
         }
-        let pt = &self.bound_types[self.col as usize];
+        // types are only sent with the first execution, and ParamParser::new checked that they were
+        let pt = self.bound_types[self.col as usize];
 
         // https://web.archive.org/web/20170404144156/https://dev.mysql.com/doc/internals/en/null-bitmap.html
         // NULL-bitmap-byte = ((field-pos + offset) / 8)
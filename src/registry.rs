@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// Connection ids are unique across the whole process, even between servers that do not share a
+// registry, so that a client never sees the same id twice.
+static NEXT_CONNECTION_ID: AtomicU32 = AtomicU32::new(1);
+
+/// A snapshot of one connection, with the information MySQL shows in `SHOW PROCESSLIST`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    /// The connection id, as returned by `CONNECTION_ID()`.
+    pub id: u32,
+    /// The user the client logged in as, or `unauthenticated user` while it is logging in.
+    pub user: String,
+    /// The address of the client, if the connection is over TCP.
+    pub host: Option<SocketAddr>,
+    /// The client's current database.
+    pub database: Option<String>,
+    /// What the connection is doing, such as `Sleep`, `Query` or `Execute`.
+    pub command: &'static str,
+    /// How long the connection has been in its current state.
+    pub time: Duration,
+    /// The query being executed, if any.
+    pub info: Option<String>,
+}
+
+/// Lets a connection's running query be interrupted with `KILL`, from another connection or
+/// through the [`ConnectionRegistry`](struct.ConnectionRegistry.html).
+///
+/// While a query or prepared statement is being executed, the server races the shim's
+/// `on_query` or `on_execute` against this token, and drops it if the token fires first. Shims
+/// that would rather stop at a convenient point can watch the token themselves through
+/// [`ConnectionContext::cancellation`](struct.ConnectionContext.html#structfield.cancellation).
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<CancellationState>,
+}
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    query: AtomicBool,
+    connection: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    /// Whether the current query has been killed.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.query.load(Ordering::SeqCst) || self.is_killed()
+    }
+
+    /// Wait until the current query is killed.
+    pub async fn cancelled(&self) {
+        self.wait(CancellationToken::is_cancelled).await
+    }
+
+    /// Whether the whole connection has been killed.
+    pub(crate) fn is_killed(&self) -> bool {
+        self.inner.connection.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the whole connection is killed.
+    pub(crate) async fn killed(&self) {
+        self.wait(CancellationToken::is_killed).await
+    }
+
+    async fn wait(&self, done: fn(&Self) -> bool) {
+        loop {
+            // register for wakeups before checking, so that a cancellation in between is not lost
+            let notified = self.inner.notify.notified();
+            if done(self) {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Run `f` to completion, unless the current query is killed first, in which case `f` is
+    /// dropped and `None` is returned.
+    pub(crate) async fn run<F: Future>(&self, f: F) -> Option<F::Output> {
+        race(f, self.cancelled()).await
+    }
+
+    /// Like `run`, but only gives up on `f` if the whole connection is killed.
+    pub(crate) async fn run_until_killed<F: Future>(&self, f: F) -> Option<F::Output> {
+        race(f, self.killed()).await
+    }
+
+    /// Forget about any `KILL QUERY` that arrived while no query was running.
+    pub(crate) fn reset(&self) {
+        self.inner.query.store(false, Ordering::SeqCst);
+    }
+
+    fn cancel(&self, connection: bool) {
+        if connection {
+            self.inner.connection.store(true, Ordering::SeqCst);
+        } else {
+            self.inner.query.store(true, Ordering::SeqCst);
+        }
+        self.inner.notify.notify_waiters();
+    }
+}
+
+async fn race<F: Future, C: Future<Output = ()>>(f: F, cancel: C) -> Option<F::Output> {
+    use futures::future::{self, Either};
+
+    futures::pin_mut!(f);
+    futures::pin_mut!(cancel);
+    match future::select(f, cancel).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    cancellation: CancellationToken,
+    user: String,
+    host: Option<SocketAddr>,
+    database: Option<String>,
+    command: &'static str,
+    since: Instant,
+    info: Option<String>,
+}
+
+impl Entry {
+    fn snapshot(&self, id: u32, now: Instant) -> ProcessInfo {
+        ProcessInfo {
+            id,
+            user: self.user.clone(),
+            host: self.host,
+            database: self.database.clone(),
+            command: self.command,
+            time: now.saturating_duration_since(self.since),
+            info: self.info.clone(),
+        }
+    }
+}
+
+/// Keeps track of the live connections of the servers that share it.
+///
+/// Every [`ServerOptions`](struct.ServerOptions.html) has a registry, which all of its clones
+/// share; get it with
+/// [`ServerOptions::connection_registry`](struct.ServerOptions.html#method.connection_registry).
+/// Unless one is set with
+/// [`ServerOptions::with_connection_registry`](struct.ServerOptions.html#method.with_connection_registry),
+/// that is a single registry for the whole process.
+/// Connections add themselves when they are accepted and remove themselves when they close.
+#[derive(Debug)]
+pub struct ConnectionRegistry {
+    connections: Mutex<HashMap<u32, Entry>>,
+    started: Instant,
+    questions: AtomicU64,
+    slow_queries: AtomicU64,
+}
+
+impl Default for ConnectionRegistry {
+    fn default() -> Self {
+        ConnectionRegistry {
+            connections: Default::default(),
+            started: Instant::now(),
+            questions: AtomicU64::new(0),
+            slow_queries: AtomicU64::new(0),
+        }
+    }
+}
+
+/// How long a statement has to run to count as a slow query, like MySQL's default
+/// `long_query_time`.
+const LONG_QUERY_TIME: Duration = Duration::from_secs(10);
+
+/// Counters about the connections in a [`ConnectionRegistry`](struct.ConnectionRegistry.html),
+/// which are what clients get in response to `COM_STATISTICS` (as sent by `mysqladmin status`).
+///
+/// Its `Display` implementation formats it like the MySQL server does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Statistics {
+    /// How long the registry has existed. For the process-wide registry connections share by
+    /// default, that is since the first `ServerOptions` was created.
+    pub uptime: Duration,
+    /// The number of live connections in the registry.
+    pub threads: usize,
+    /// The number of commands clients have sent, not counting administrative ones like
+    /// `COM_PING`, or those that prepare and close statements.
+    pub questions: u64,
+    /// The number of those commands that took more than 10 seconds to complete.
+    pub slow_queries: u64,
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let uptime = self.uptime.as_secs();
+        write!(
+            f,
+            "Uptime: {}  Threads: {}  Questions: {}  Slow queries: {}  Queries per second avg: {:.3}",
+            uptime,
+            self.threads,
+            self.questions,
+            self.slow_queries,
+            self.questions as f64 / uptime.max(1) as f64
+        )
+    }
+}
+
+impl ConnectionRegistry {
+    /// Create a registry with no connections in it.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All live connections, ordered by id.
+    pub fn processes(&self) -> Vec<ProcessInfo> {
+        let now = Instant::now();
+        let connections = self.connections.lock().unwrap();
+        let mut processes: Vec<_> = connections
+            .iter()
+            .map(|(&id, entry)| entry.snapshot(id, now))
+            .collect();
+        processes.sort_by_key(|p| p.id);
+        processes
+    }
+
+    /// The connection with the given id, if it is still live.
+    pub fn process(&self, id: u32) -> Option<ProcessInfo> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .get(&id)
+            .map(|entry| entry.snapshot(id, Instant::now()))
+    }
+
+    /// The current values of the counters kept about the registry's connections.
+    pub fn statistics(&self) -> Statistics {
+        Statistics {
+            uptime: self.started.elapsed(),
+            threads: self.connections.lock().unwrap().len(),
+            questions: self.questions.load(Ordering::Relaxed),
+            slow_queries: self.slow_queries.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a command a client sent, which took `elapsed` to complete.
+    pub(crate) fn record_question(&self, elapsed: Duration) {
+        self.questions.fetch_add(1, Ordering::Relaxed);
+        if elapsed > LONG_QUERY_TIME {
+            self.slow_queries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Interrupt the query the connection with the given id is running, if any, as `KILL QUERY`
+    /// does. Returns `false` if there is no such connection.
+    pub fn kill_query(&self, id: u32) -> bool {
+        self.cancel(id, false)
+    }
+
+    /// Close the connection with the given id, interrupting its query if it is running one, as
+    /// `KILL CONNECTION` does. Returns `false` if there is no such connection.
+    pub fn kill_connection(&self, id: u32) -> bool {
+        self.cancel(id, true)
+    }
+
+    fn cancel(&self, id: u32, connection: bool) -> bool {
+        let connections = self.connections.lock().unwrap();
+        match connections.get(&id) {
+            Some(entry) => {
+                entry.cancellation.cancel(connection);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn register(self: &Arc<Self>, host: Option<SocketAddr>) -> Registration {
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().unwrap().insert(
+            id,
+            Entry {
+                cancellation: CancellationToken::default(),
+                user: "unauthenticated user".to_owned(),
+                host,
+                database: None,
+                command: "Connect",
+                since: Instant::now(),
+                info: None,
+            },
+        );
+        Registration {
+            registry: self.clone(),
+            id,
+        }
+    }
+}
+
+/// A connection's entry in a [`ConnectionRegistry`], which is removed again when this is dropped.
+pub(crate) struct Registration {
+    registry: Arc<ConnectionRegistry>,
+    id: u32,
+}
+
+impl Registration {
+    pub(crate) fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub(crate) fn cancellation(&self) -> CancellationToken {
+        let connections = self.registry.connections.lock().unwrap();
+        connections[&self.id].cancellation.clone()
+    }
+
+    fn update<F: FnOnce(&mut Entry)>(&self, f: F) {
+        if let Some(entry) = self.registry.connections.lock().unwrap().get_mut(&self.id) {
+            f(entry);
+        }
+    }
+
+    pub(crate) fn set_user(&self, user: &str) {
+        self.update(|entry| entry.user = user.to_owned());
+    }
+
+    pub(crate) fn set_database(&self, database: Option<&str>) {
+        self.update(|entry| entry.database = database.map(str::to_owned));
+    }
+
+    /// Record that the connection started doing `command`, on `info` if it is a query.
+    pub(crate) fn set_command(&self, command: &'static str, info: Option<&str>) {
+        self.update(|entry| {
+            entry.command = command;
+            entry.since = Instant::now();
+            entry.info = info.map(str::to_owned);
+        });
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_tracks_connections() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let a = registry.register(None);
+        let b = registry.register(None);
+        assert!(b.id() > a.id());
+
+        a.set_user("jon");
+        a.set_database(Some("db"));
+        a.set_command("Query", Some("SELECT 1"));
+        let process = registry.process(a.id()).unwrap();
+        assert_eq!(process.user, "jon");
+        assert_eq!(process.database.as_deref(), Some("db"));
+        assert_eq!(process.command, "Query");
+        assert_eq!(process.info.as_deref(), Some("SELECT 1"));
+
+        let ids: Vec<_> = registry.processes().iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![a.id(), b.id()]);
+
+        let id = a.id();
+        drop(a);
+        assert_eq!(registry.process(id), None);
+        assert_eq!(registry.processes().len(), 1);
+        assert!(!registry.kill_query(id));
+    }
+
+    #[test]
+    fn it_cancels_connections() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let conn = registry.register(None);
+        let token = conn.cancellation();
+
+        assert!(registry.kill_query(conn.id()));
+        assert!(token.is_cancelled());
+        assert!(!token.is_killed());
+        token.reset();
+        assert!(!token.is_cancelled());
+
+        assert!(registry.kill_connection(conn.id()));
+        assert!(token.is_cancelled());
+        assert!(token.is_killed());
+    }
+
+    #[test]
+    fn it_counts_questions() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let _conn = registry.register(None);
+        registry.record_question(Duration::from_millis(1));
+        registry.record_question(Duration::from_secs(11));
+
+        let statistics = registry.statistics();
+        assert_eq!(statistics.threads, 1);
+        assert_eq!(statistics.questions, 2);
+        assert_eq!(statistics.slow_queries, 1);
+
+        let statistics = Statistics {
+            uptime: Duration::from_secs(4),
+            ..statistics
+        };
+        assert_eq!(
+            statistics.to_string(),
+            "Uptime: 4  Threads: 1  Questions: 2  Slow queries: 1  Queries per second avg: 0.500"
+        );
+    }
+}
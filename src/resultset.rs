@@ -2,23 +2,45 @@ use crate::myc::constants::{ColumnFlags, StatusFlags};
 use crate::packet::PacketWriter;
 use crate::value::ToMysqlValue;
 use crate::writers;
-use crate::{Column, ErrorKind, StatementData};
+use crate::{Column, ErrorKind, SessionStateChange, StatementData, Warning};
 use byteorder::WriteBytesExt;
+use futures::stream::{Stream, StreamExt};
 use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::io::{self, Write};
+use std::pin::Pin;
 
 /// Convenience type for responding to a client `USE <db>` command.
 pub struct InitWriter<'a> {
     pub(crate) writer: &'a mut PacketWriter,
+    pub(crate) schema: &'a str,
+    // set once the shim accepts the new database
+    pub(crate) changed: &'a mut bool,
 }
 
 impl<'a> InitWriter<'a> {
     /// Tell client that database context has been changed
+    ///
+    /// Clients that track their session state are told about the new schema.
     pub fn ok(self) -> io::Result<()> {
+        *self.changed = true;
+        self.writer
+            .add_session_state(&SessionStateChange::Schema(self.schema.to_owned()))?;
         writers::write_ok_packet(self.writer, 0, 0, StatusFlags::empty())
     }
 
+    /// Turn the given status flags of the session on or off. See
+    /// [`QueryResultWriter::set_status_flags`](struct.QueryResultWriter.html#method.set_status_flags).
+    pub fn set_status_flags(&mut self, flags: StatusFlags, enabled: bool) {
+        self.writer.toggle_status_flags(flags, enabled);
+    }
+
+    /// Tell clients that track their session state that changing the database also changed
+    /// the session in the given way, such as by setting `character_set_database`.
+    pub fn session_state(&mut self, change: &SessionStateChange) -> io::Result<()> {
+        self.writer.add_session_state(change)
+    }
+
     /// Tell client that there was a problem changing the database context.
     /// Although you can return any valid MySQL error code you probably want
     /// to keep it similar to the MySQL server and issue either a
@@ -68,6 +90,12 @@ impl<'a> StatementMetaWriter<'a> {
         writers::write_prepare_ok(id, params, columns, self.writer)
     }
 
+    /// Record a warning raised while preparing the statement, which is counted in the reply, and
+    /// listed by the client's next `SHOW WARNINGS`.
+    pub fn warning(&mut self, warning: Warning) {
+        self.writer.add_warning(warning);
+    }
+
     /// Reply to the client's `PREPARE` with an error.
     pub async fn error<E>(self, kind: ErrorKind, msg: &E) -> io::Result<()>
     where
@@ -77,8 +105,78 @@ impl<'a> StatementMetaWriter<'a> {
     }
 }
 
+/// Convenience type for responding to a client `COM_FIELD_LIST` command.
+///
+/// This type should not be dropped without calling
+/// [`reply`](struct.ListFieldsWriter.html#method.reply) or
+/// [`error`](struct.ListFieldsWriter.html#method.error).
+#[must_use]
+pub struct ListFieldsWriter<'a> {
+    pub(crate) writer: &'a mut PacketWriter,
+}
+
+impl<'a> ListFieldsWriter<'a> {
+    /// Reply to the client with the definitions of the given `columns`.
+    pub fn reply<I>(self, columns: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = &'a Column>,
+    {
+        writers::write_field_list(columns, self.writer)
+    }
+
+    /// Reply to the client's `COM_FIELD_LIST` with an error.
+    ///
+    /// For tables that do not exist, you probably want to do like the MySQL server, and send
+    /// `ErrorKind::ER_NO_SUCH_TABLE` with a message like `Table 'db.table' doesn't exist`.
+    pub async fn error<E>(self, kind: ErrorKind, msg: &E) -> io::Result<()>
+    where
+        E: Borrow<[u8]> + ?Sized,
+    {
+        writers::write_err(kind, msg.borrow(), self.writer)
+    }
+}
+
+/// The rows of a resultset that a client reads through a cursor, produced as the client asks for
+/// them. See [`QueryResultWriter::open_cursor`](struct.QueryResultWriter.html#method.open_cursor).
+pub type RowStream = Pin<Box<dyn Stream<Item = io::Result<Vec<myc::value::Value>>> + Send>>;
+
+/// A resultset whose rows are left to be fetched by the client.
+pub(crate) struct Cursor {
+    columns: Vec<Column>,
+    rows: RowStream,
+}
+
+impl Cursor {
+    /// Send the client up to `n` more rows.
+    ///
+    /// Returns `true` once all the rows have been sent, after which the cursor should be closed.
+    pub(crate) async fn fetch(&mut self, n: u32, writer: &mut PacketWriter) -> io::Result<bool> {
+        let mut rw = RowWriter::new(QueryResultWriter::new(writer, true), &self.columns);
+        let mut done = false;
+        for _ in 0..n {
+            match self.rows.next().await {
+                Some(row) => rw.write_row(row?)?,
+                None => {
+                    done = true;
+                    break;
+                }
+            }
+        }
+        let writer = rw.result.take().unwrap().writer;
+
+        let mut status = StatusFlags::SERVER_STATUS_CURSOR_EXISTS;
+        status.set(StatusFlags::SERVER_STATUS_LAST_ROW_SENT, done);
+        writers::write_eof_packet(writer, status)?;
+        Ok(done)
+    }
+}
+
 enum Finalizer {
-    Ok { rows: u64, last_insert_id: u64 },
+    Ok {
+        rows: u64,
+        last_insert_id: u64,
+        info: Vec<u8>,
+    },
     EOF,
 }
 
@@ -105,6 +203,8 @@ pub struct QueryResultWriter<'a> {
     pub(crate) is_bin: bool,
     pub(crate) writer: &'a mut PacketWriter,
     last_end: Option<Finalizer>,
+    // where to keep the cursor, if the client asked for one
+    cursor: Option<&'a mut Option<Cursor>>,
 }
 
 impl<'a> QueryResultWriter<'a> {
@@ -113,9 +213,16 @@ impl<'a> QueryResultWriter<'a> {
             is_bin,
             writer,
             last_end: None,
+            cursor: None,
         }
     }
 
+    /// Let the results be read through a cursor, which is kept in `cursor`.
+    pub(crate) fn with_cursor(mut self, cursor: &'a mut Option<Cursor>) -> Self {
+        self.cursor = Some(cursor);
+        self
+    }
+
     async fn finalize(&mut self, more_exists: bool) -> io::Result<()> {
         let mut status = StatusFlags::empty();
         if more_exists {
@@ -126,7 +233,11 @@ impl<'a> QueryResultWriter<'a> {
             Some(Finalizer::Ok {
                 rows,
                 last_insert_id,
-            }) => writers::write_ok_packet(self.writer, rows, last_insert_id, status)?,
+                info,
+            }) => {
+                self.writer.set_info(&info);
+                writers::write_ok_packet(self.writer, rows, last_insert_id, status)?
+            }
             Some(Finalizer::EOF) => writers::write_eof_packet(self.writer, status)?,
         }
         self.writer.flush_all().await
@@ -156,6 +267,8 @@ impl<'a> QueryResultWriter<'a> {
         self.last_end = Some(Finalizer::Ok {
             rows,
             last_insert_id,
+            // the OK packet is only written once the next resultset starts
+            info: self.writer.take_info(),
         });
         Ok(self)
     }
@@ -168,6 +281,88 @@ impl<'a> QueryResultWriter<'a> {
         r.no_more_results().await
     }
 
+    /// Tell clients that track their session state that the query changed it in the given way,
+    /// for example that `SET autocommit = 0` set a system variable.
+    ///
+    /// The changes are sent with the next OK packet, which ends the response to a
+    /// [`completed`](struct.QueryResultWriter.html#method.completed) query, and with clients that
+    /// negotiated `CLIENT_DEPRECATE_EOF`, a resultset.
+    pub fn session_state(&mut self, change: &SessionStateChange) -> io::Result<()> {
+        self.writer.add_session_state(change)
+    }
+
+    /// The status flags of the session, which are sent with every OK and EOF packet.
+    pub fn status_flags(&self) -> StatusFlags {
+        self.writer.status_flags()
+    }
+
+    /// Turn the given status flags of the session on or off, for example
+    /// `SERVER_STATUS_IN_TRANS` once the query started a transaction.
+    ///
+    /// The flags stay that way for the following commands, until they are changed again. Flags
+    /// that only describe a single response, such as `SERVER_MORE_RESULTS_EXISTS`, are taken care
+    /// of by the server.
+    pub fn set_status_flags(&mut self, flags: StatusFlags, enabled: bool) {
+        self.writer.toggle_status_flags(flags, enabled);
+    }
+
+    /// Record a warning raised by the query, which is counted in the OK or EOF packet that ends
+    /// the response, and listed by the client's next `SHOW WARNINGS`.
+    pub fn warning(&mut self, warning: Warning) {
+        self.writer.add_warning(warning);
+    }
+
+    /// Send `info` as the human-readable summary of what the query did, such as
+    /// `Rows matched: 3  Changed: 2  Warnings: 0`, in the OK packet sent by
+    /// [`completed`](struct.QueryResultWriter.html#method.completed) or
+    /// [`complete_one`](struct.QueryResultWriter.html#method.complete_one).
+    pub fn info(&mut self, info: &str) {
+        self.writer.set_info(info.as_bytes());
+    }
+
+    /// Whether the client asked to read the results through a cursor, as it does for a prepared
+    /// statement executed with `CURSOR_TYPE_READ_ONLY`.
+    ///
+    /// Such clients are best answered with
+    /// [`open_cursor`](struct.QueryResultWriter.html#method.open_cursor).
+    pub fn cursor_requested(&self) -> bool {
+        self.cursor.is_some()
+    }
+
+    /// Reply with a resultset that conforms to the given `columns`, and whose rows are taken from
+    /// `rows` only as the client fetches them.
+    ///
+    /// This lets huge resultsets be streamed to clients that cannot hold them in memory. If the
+    /// client did not ask for a cursor, all of `rows` is sent right away instead.
+    pub async fn open_cursor(
+        mut self,
+        columns: &'a [Column],
+        mut rows: RowStream,
+    ) -> io::Result<()> {
+        let cursor = match self.cursor.take().filter(|_| !columns.is_empty()) {
+            Some(cursor) => cursor,
+            None => {
+                let mut rw = self.start(columns).await?;
+                while let Some(row) = rows.next().await {
+                    rw.write_row(row?)?;
+                }
+                return rw.finish().await;
+            }
+        };
+
+        self.finalize(true).await?;
+        writers::column_definitions(
+            columns,
+            StatusFlags::SERVER_STATUS_CURSOR_EXISTS,
+            self.writer,
+        )?;
+        *cursor = Some(Cursor {
+            columns: columns.to_vec(),
+            rows,
+        });
+        Ok(())
+    }
+
     /// Reply to the client's query with an error.
     pub async fn error<E>(mut self, kind: ErrorKind, msg: &E) -> io::Result<()>
     where
@@ -229,7 +424,11 @@ impl<'a> RowWriter<'a> {
     #[inline]
     fn start(&mut self) -> io::Result<()> {
         if !self.columns.is_empty() {
-            writers::column_definitions(self.columns, self.result.as_mut().unwrap().writer)?;
+            writers::column_definitions(
+                self.columns,
+                StatusFlags::empty(),
+                self.result.as_mut().unwrap().writer,
+            )?;
         }
         Ok(())
     }
@@ -321,6 +520,23 @@ impl<'a> RowWriter<'a> {
         Ok(())
     }
 
+    /// Turn the given status flags of the session on or off, which the EOF packet that ends the
+    /// resultset already carries. See
+    /// [`QueryResultWriter::set_status_flags`](struct.QueryResultWriter.html#method.set_status_flags).
+    pub fn set_status_flags(&mut self, flags: StatusFlags, enabled: bool) {
+        self.result
+            .as_mut()
+            .unwrap()
+            .writer
+            .toggle_status_flags(flags, enabled);
+    }
+
+    /// Record a warning raised while producing the rows, which is counted in the EOF packet that
+    /// ends the resultset, and listed by the client's next `SHOW WARNINGS`.
+    pub fn warning(&mut self, warning: Warning) {
+        self.result.as_mut().unwrap().writer.add_warning(warning);
+    }
+
     /// Write a single row as a part of this resultset.
     ///
     /// Note that the row *must* conform to the column specification provided to
@@ -355,9 +571,11 @@ impl<'a> RowWriter<'a> {
         if self.columns.is_empty() {
             // response to no column query is always an OK packet
             // we've kept track of the number of rows in col (hacky, I know)
-            self.result.as_mut().unwrap().last_end = Some(Finalizer::Ok {
+            let result = self.result.as_mut().unwrap();
+            result.last_end = Some(Finalizer::Ok {
                 rows: self.col as u64,
                 last_insert_id: 0,
+                info: result.writer.take_info(),
             });
         } else {
             // we wrote out at least one row
@@ -0,0 +1,90 @@
+use crate::myc::io::WriteMysqlExt;
+use byteorder::WriteBytesExt;
+use std::io;
+
+/// A change to the state of a client's session, which clients that negotiated
+/// `CLIENT_SESSION_TRACK` are told about in the OK packet that ends the command's response.
+///
+/// Connection pools and proxies use these to learn that, for example, a `USE` or a
+/// `SET autocommit = 0` left the session in a different state than they handed it out in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionStateChange {
+    /// The system variable `name` was set to `value`.
+    SystemVariable {
+        /// The name of the variable, without `@@`.
+        name: String,
+        /// The new value of the variable.
+        value: String,
+    },
+    /// The current schema was changed to the given one.
+    Schema(String),
+    /// Some part of the session's state that has no more specific change changed, such as a user
+    /// variable or a temporary table.
+    StateChange,
+    /// The GTIDs of the transactions the session committed, in MySQL's GTID set format.
+    Gtids(String),
+    /// The statements that would start a transaction with the same characteristics as the
+    /// current one, such as `START TRANSACTION READ ONLY;`.
+    TransactionCharacteristics(String),
+    /// The state of the current transaction, in the eight character format of MySQL's
+    /// `session_track_transaction_info`, such as `T_______`.
+    TransactionState(String),
+}
+
+impl SessionStateChange {
+    /// Encode the change as an entry of the session state information of an OK packet.
+    pub(crate) fn write(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        let mut data = Vec::new();
+        let kind = match self {
+            SessionStateChange::SystemVariable { name, value } => {
+                data.write_lenenc_str(name.as_bytes())?;
+                data.write_lenenc_str(value.as_bytes())?;
+                0x00 // SESSION_TRACK_SYSTEM_VARIABLES
+            }
+            SessionStateChange::Schema(schema) => {
+                data.write_lenenc_str(schema.as_bytes())?;
+                0x01 // SESSION_TRACK_SCHEMA
+            }
+            SessionStateChange::StateChange => {
+                data.write_lenenc_str(b"1")?;
+                0x02 // SESSION_TRACK_STATE_CHANGE
+            }
+            SessionStateChange::Gtids(gtids) => {
+                data.write_u8(0x00)?; // the only encoding specification there is
+                data.write_lenenc_str(gtids.as_bytes())?;
+                0x03 // SESSION_TRACK_GTIDS
+            }
+            SessionStateChange::TransactionCharacteristics(characteristics) => {
+                data.write_lenenc_str(characteristics.as_bytes())?;
+                0x04 // SESSION_TRACK_TRANSACTION_CHARACTERISTICS
+            }
+            SessionStateChange::TransactionState(state) => {
+                data.write_lenenc_str(state.as_bytes())?;
+                0x05 // SESSION_TRACK_TRANSACTION_STATE
+            }
+        };
+        w.write_u8(kind)?;
+        w.write_lenenc_str(&data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_encodes_changes() {
+        let mut w = Vec::new();
+        SessionStateChange::Schema("db".to_owned())
+            .write(&mut w)
+            .unwrap();
+        SessionStateChange::SystemVariable {
+            name: "autocommit".to_owned(),
+            value: "OFF".to_owned(),
+        }
+        .write(&mut w)
+        .unwrap();
+        assert_eq!(w, b"\x01\x03\x02db\x00\x0f\x0aautocommit\x03OFF".to_vec());
+    }
+}
@@ -0,0 +1,156 @@
+/// Split `q` into the statements it is made of, at the `;`s that are not inside a string, a
+/// quoted identifier or a comment.
+///
+/// Surrounding whitespace and the separating `;`s are removed, and statements that are empty (or
+/// only contain comments) are left out.
+pub(crate) fn statements(q: &[u8]) -> Vec<&[u8]> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    // whether the current statement has anything but whitespace and comments in it
+    let mut content = false;
+    let mut i = 0;
+    while i < q.len() {
+        match q[i] {
+            quote @ (b'\'' | b'"' | b'`') => {
+                content = true;
+                i = skip_quoted(q, i, quote);
+                continue;
+            }
+            b'#' => i = skip_line(q, i),
+            b'-' if q[i..].starts_with(b"--")
+                && q.get(i + 2).filter(|b| !b.is_ascii_whitespace()).is_none() =>
+            {
+                i = skip_line(q, i)
+            }
+            b'/' if q[i..].starts_with(b"/*") => {
+                // `/*! ... */` and `/*+ ... */` are executed or used as hints by MySQL
+                if matches!(q.get(i + 2), Some(b'!') | Some(b'+')) {
+                    content = true;
+                }
+                i = match q[i + 2..].windows(2).position(|w| w == b"*/") {
+                    Some(end) => i + 2 + end + 2,
+                    None => q.len(),
+                };
+                continue;
+            }
+            b';' => {
+                if content {
+                    statements.push(trim(&q[start..i]));
+                }
+                start = i + 1;
+                content = false;
+            }
+            b if b.is_ascii_whitespace() => {}
+            _ => content = true,
+        }
+        i += 1;
+    }
+    if content {
+        statements.push(trim(&q[start..]));
+    }
+    statements
+}
+
+/// Find the end of the string or identifier quoted with `quote` that starts at `start`.
+fn skip_quoted(q: &[u8], start: usize, quote: u8) -> usize {
+    let mut i = start + 1;
+    while i < q.len() {
+        if q[i] == b'\\' && quote != b'`' {
+            i += 2;
+        } else if q[i] == quote {
+            // a doubled quote stands for the quote itself
+            if q.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    q.len()
+}
+
+/// Find the end of the line that `start` is on.
+fn skip_line(q: &[u8], start: usize) -> usize {
+    match q[start..].iter().position(|&b| b == b'\n') {
+        Some(end) => start + end,
+        None => q.len(),
+    }
+}
+
+fn trim(mut s: &[u8]) -> &[u8] {
+    while let Some((first, rest)) = s.split_first() {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        s = rest;
+    }
+    while let Some((last, rest)) = s.split_last() {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        s = rest;
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(q: &str) -> Vec<&str> {
+        statements(q.as_bytes())
+            .into_iter()
+            .map(|s| std::str::from_utf8(s).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn it_splits_statements() {
+        assert_eq!(split("SELECT 1"), vec!["SELECT 1"]);
+        assert_eq!(split("SELECT 1; SELECT 2;"), vec!["SELECT 1", "SELECT 2"]);
+        assert_eq!(split(" ;; SELECT 1 ;\n"), vec!["SELECT 1"]);
+        assert_eq!(split(""), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn it_ignores_separators_in_quotes() {
+        assert_eq!(
+            split(r#"SELECT 'a;b', "c;d"; SELECT `e;f`"#),
+            vec![r#"SELECT 'a;b', "c;d""#, "SELECT `e;f`"]
+        );
+        assert_eq!(
+            split(r"SELECT 'it''s;', 'back\';slash'; SELECT 2"),
+            vec![r"SELECT 'it''s;', 'back\';slash'", "SELECT 2"]
+        );
+        assert_eq!(
+            split("SELECT `a``;b`; SELECT 2"),
+            vec!["SELECT `a``;b`", "SELECT 2"]
+        );
+        assert_eq!(
+            split("SELECT 'unterminated; SELECT 2"),
+            vec!["SELECT 'unterminated; SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn it_ignores_separators_in_comments() {
+        assert_eq!(
+            split("SELECT 1 -- one; two\n; SELECT 2 # three; four\n"),
+            vec!["SELECT 1 -- one; two", "SELECT 2 # three; four"]
+        );
+        assert_eq!(
+            split("SELECT 1 /* ; */; /* only a comment */; SELECT 3-- 1"),
+            vec!["SELECT 1 /* ; */", "SELECT 3-- 1"]
+        );
+        assert_eq!(
+            split("SELECT 1 --1; SELECT 2"),
+            vec!["SELECT 1 --1", "SELECT 2"]
+        );
+        assert_eq!(
+            split("/*!40101 SET NAMES utf8 */"),
+            vec!["/*!40101 SET NAMES utf8 */"]
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use crate::packet::{PacketBuff, PacketWriter, Stream};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// Upgrade the connection to TLS after the client has sent an `SSLRequest`.
+///
+/// Clients start the TLS handshake immediately after the `SSLRequest` without waiting for the
+/// server, so some of it may already be sitting in `reader`'s buffer. Those bytes are replayed to
+/// the TLS acceptor before anything else is read from the stream.
+pub(crate) async fn accept(
+    config: Arc<ServerConfig>,
+    writer: &mut PacketWriter,
+    reader: &mut PacketBuff,
+) -> io::Result<()> {
+    let stream = Rewind {
+        prefix: reader.take_unread(),
+        pos: 0,
+        inner: writer.take_stream(),
+    };
+    let stream = TlsAcceptor::from(config).accept(stream).await?;
+    writer.set_stream(Box::new(stream));
+    Ok(())
+}
+
+struct Rewind {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: Box<dyn Stream>,
+}
+
+impl AsyncRead for Rewind {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let n = std::cmp::min(buf.remaining(), self.prefix.len() - self.pos);
+            buf.put_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Rewind {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
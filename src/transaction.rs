@@ -0,0 +1,116 @@
+use crate::builtins::{normalize, starts_with_keyword};
+use crate::myc::constants::StatusFlags;
+
+/// A statement that changes the transaction state of the session, as tracked for
+/// [`ServerOptions::with_transaction_tracking`](struct.ServerOptions.html#method.with_transaction_tracking).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TransactionChange {
+    /// `BEGIN [WORK]` or `START TRANSACTION ...`
+    Begin,
+    /// `COMMIT [WORK]` or `ROLLBACK [WORK]`
+    End,
+    /// `SET [SESSION] autocommit = ...`
+    Autocommit(bool),
+}
+
+impl TransactionChange {
+    pub(crate) fn parse(q: &[u8]) -> Option<TransactionChange> {
+        if !starts_with_keyword(q, &[b"begin", b"start", b"commit", b"rollback", b"set"]) {
+            return None;
+        }
+        let q = normalize(q);
+        match &q[..] {
+            b"begin" | b"begin work" => Some(TransactionChange::Begin),
+            _ if q == b"start transaction" || q.starts_with(b"start transaction ") => {
+                Some(TransactionChange::Begin)
+            }
+            b"commit" | b"commit work" | b"rollback" | b"rollback work" => {
+                Some(TransactionChange::End)
+            }
+            _ if q.starts_with(b"set ") => {
+                let assignment: Vec<u8> = q[b"set ".len()..]
+                    .iter()
+                    .copied()
+                    .filter(|&b| b != b' ')
+                    .collect();
+                let assignment = [&b"session"[..], b"@@session.", b"@@local.", b"@@"]
+                    .iter()
+                    .find_map(|prefix| assignment.strip_prefix(*prefix))
+                    .unwrap_or(&assignment);
+                match assignment.strip_prefix(b"autocommit=")? {
+                    b"1" | b"on" | b"true" | b"'on'" => Some(TransactionChange::Autocommit(true)),
+                    b"0" | b"off" | b"false" | b"'off'" => {
+                        Some(TransactionChange::Autocommit(false))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The status flags of the session once the statement has run.
+    pub(crate) fn apply(self, mut status: StatusFlags) -> StatusFlags {
+        match self {
+            TransactionChange::Begin => status.insert(StatusFlags::SERVER_STATUS_IN_TRANS),
+            TransactionChange::End => status.remove(StatusFlags::SERVER_STATUS_IN_TRANS),
+            TransactionChange::Autocommit(true) => {
+                // turning autocommit on commits the current transaction
+                status.insert(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+                status.remove(StatusFlags::SERVER_STATUS_IN_TRANS);
+            }
+            TransactionChange::Autocommit(false) => {
+                status.remove(StatusFlags::SERVER_STATUS_AUTOCOMMIT)
+            }
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_transaction_statements() {
+        let parse = |q: &str| TransactionChange::parse(q.as_bytes());
+        assert_eq!(parse("BEGIN"), Some(TransactionChange::Begin));
+        assert_eq!(
+            parse("START TRANSACTION READ ONLY;"),
+            Some(TransactionChange::Begin)
+        );
+        assert_eq!(parse("commit work"), Some(TransactionChange::End));
+        assert_eq!(parse("ROLLBACK"), Some(TransactionChange::End));
+        assert_eq!(parse("ROLLBACK TO SAVEPOINT a"), None);
+        assert_eq!(
+            parse("SET autocommit=0"),
+            Some(TransactionChange::Autocommit(false))
+        );
+        assert_eq!(
+            parse("set @@SESSION.autocommit = ON"),
+            Some(TransactionChange::Autocommit(true))
+        );
+        assert_eq!(parse("SET autocommit_foo = 1"), None);
+        assert_eq!(parse("SELECT 1"), None);
+    }
+
+    #[test]
+    fn it_tracks_transaction_status() {
+        let status = StatusFlags::SERVER_STATUS_AUTOCOMMIT;
+        let status = TransactionChange::Begin.apply(status);
+        assert_eq!(
+            status,
+            StatusFlags::SERVER_STATUS_AUTOCOMMIT | StatusFlags::SERVER_STATUS_IN_TRANS
+        );
+        assert_eq!(
+            TransactionChange::End.apply(status),
+            StatusFlags::SERVER_STATUS_AUTOCOMMIT
+        );
+        let status = TransactionChange::Autocommit(false).apply(status);
+        assert_eq!(status, StatusFlags::SERVER_STATUS_IN_TRANS);
+        assert_eq!(
+            TransactionChange::Autocommit(true).apply(status),
+            StatusFlags::SERVER_STATUS_AUTOCOMMIT
+        );
+    }
+}
@@ -301,6 +301,7 @@ mod tests {
                     column: String::new(),
                     coltype: $ct,
                     colflags: ColumnFlags::empty(),
+                    ..Default::default()
                 };
 
                 if !$sig {
@@ -739,6 +739,7 @@ mod tests {
                         column: String::new(),
                         coltype: $ct,
                         colflags: ColumnFlags::empty(),
+                        ..Default::default()
                     };
 
                     if !$sig {
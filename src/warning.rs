@@ -0,0 +1,37 @@
+use crate::ErrorKind;
+
+/// How serious a [`Warning`](struct.Warning.html) is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningLevel {
+    /// Something the client may want to know about, such as that `DROP TABLE IF EXISTS` found no
+    /// table to drop.
+    Note,
+    /// Something that may not have gone the way the client meant it to, such as a value that
+    /// had to be truncated.
+    Warning,
+    /// The error the statement failed with.
+    Error,
+}
+
+impl WarningLevel {
+    /// The name of the level, as listed by `SHOW WARNINGS`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WarningLevel::Note => "Note",
+            WarningLevel::Warning => "Warning",
+            WarningLevel::Error => "Error",
+        }
+    }
+}
+
+/// A condition a statement raised without failing, which the client is told the number of, and
+/// can list with `SHOW WARNINGS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    /// How serious the condition is.
+    pub level: WarningLevel,
+    /// The error code of the condition, such as `ErrorKind::WARN_DATA_TRUNCATED`.
+    pub code: ErrorKind,
+    /// A description of the condition.
+    pub message: String,
+}
@@ -1,12 +1,20 @@
 use crate::myc::constants::StatusFlags;
 use crate::myc::io::WriteMysqlExt;
 use crate::packet::PacketWriter;
-use crate::{Column, ErrorKind};
+use crate::{Column, ErrorKind, Warning, WarningLevel};
 use byteorder::{LittleEndian, WriteBytesExt};
 use std::io::{self, Write};
 
+/// Write an EOF packet, or, if the client deprecated those, the OK packet with an EOF header
+/// that takes its place.
 pub(crate) fn write_eof_packet(w: &mut PacketWriter, s: StatusFlags) -> io::Result<()> {
-    w.write_all(&[0xFE, 0x00, 0x00])?;
+    if w.deprecate_eof() {
+        w.write_u8(0xFE)?;
+        return write_ok_body(w, 0, 0, s);
+    }
+    let s = s | w.status();
+    w.write_u8(0xFE)?;
+    w.write_u16::<LittleEndian>(w.warning_count())?;
     w.write_u16::<LittleEndian>(s.bits())?;
     w.end_packet();
     Ok(())
@@ -19,15 +27,51 @@ pub(crate) fn write_ok_packet(
     s: StatusFlags,
 ) -> io::Result<()> {
     w.write_u8(0x00)?; // OK packet type
+    write_ok_body(w, rows, last_insert_id, s)
+}
+
+/// Write what follows the header of an OK packet, including the info string and any session state
+/// changes the client has to be told about.
+fn write_ok_body(
+    w: &mut PacketWriter,
+    rows: u64,
+    last_insert_id: u64,
+    s: StatusFlags,
+) -> io::Result<()> {
+    let session_state = w.take_session_state();
+    let info = w.take_info();
+    let mut s = s | w.status();
+    s.set(
+        StatusFlags::SERVER_SESSION_STATE_CHANGED,
+        !session_state.is_empty(),
+    );
     w.write_lenenc_int(rows)?;
     w.write_lenenc_int(last_insert_id)?;
     w.write_u16::<LittleEndian>(s.bits())?;
-    w.write_all(&[0x00, 0x00])?; // no warnings
+    w.write_u16::<LittleEndian>(w.warning_count())?;
+    if !session_state.is_empty() {
+        w.write_lenenc_str(&info)?;
+        w.write_lenenc_str(&session_state)?;
+    } else if w.session_track() {
+        if !info.is_empty() {
+            w.write_lenenc_str(&info)?;
+        }
+    } else {
+        // without session tracking, the info string is simply the rest of the packet
+        w.write_all(&info)?;
+    }
     w.end_packet();
     Ok(())
 }
 
 pub fn write_err(err: ErrorKind, msg: &[u8], w: &mut PacketWriter) -> io::Result<()> {
+    w.set_failed();
+    // like MySQL, the error is listed by SHOW WARNINGS after the failed statement
+    w.add_warning(Warning {
+        level: WarningLevel::Error,
+        code: err,
+        message: String::from_utf8_lossy(msg).into_owned(),
+    });
     w.write_u8(0xFF)?;
     w.write_u16::<LittleEndian>(err as u16)?;
     w.write_u8(b'#')?;
@@ -37,7 +81,28 @@ pub fn write_err(err: ErrorKind, msg: &[u8], w: &mut PacketWriter) -> io::Result
     Ok(())
 }
 
-use std::borrow::Borrow;
+/// Send an `AuthMoreData` packet carrying `data` during the authentication exchange.
+pub(crate) fn write_auth_more_data(data: &[u8], w: &mut PacketWriter) -> io::Result<()> {
+    w.write_u8(0x01)?;
+    w.write_all(data)?;
+    w.end_packet();
+    Ok(())
+}
+
+/// Ask the client to authenticate again with the plugin called `plugin`, using `data` as its
+/// challenge.
+pub(crate) fn write_auth_switch_request(
+    plugin: &str,
+    data: &[u8],
+    w: &mut PacketWriter,
+) -> io::Result<()> {
+    w.write_u8(0xFE)?;
+    w.write_all(plugin.as_bytes())?;
+    w.write_u8(0x00)?;
+    w.write_all(data)?;
+    w.end_packet();
+    Ok(())
+}
 
 pub(crate) fn write_prepare_ok<'a, PI, CI>(
     id: u32,
@@ -60,50 +125,88 @@ where
     w.write_u16::<LittleEndian>(ci.len() as u16)?;
     w.write_u16::<LittleEndian>(pi.len() as u16)?;
     w.write_u8(0x00)?;
-    w.write_u16::<LittleEndian>(0)?; // number of warnings
+    w.write_u16::<LittleEndian>(w.warning_count())?;
     w.end_packet();
 
-    write_column_definitions(pi, w, true)?;
-    write_column_definitions(ci, w, true)
+    if w.deprecate_eof() {
+        // the client knows how many definitions to expect, so they are not followed by EOFs
+        for c in pi.chain(ci) {
+            write_column_definition(c, false, w)?;
+        }
+        return Ok(());
+    }
+    write_column_definitions(pi, w)?;
+    write_column_definitions(ci, w)
 }
 
-pub(crate) fn write_column_definitions<'a, I>(
-    i: I,
-    w: &mut PacketWriter,
-    only_eof_on_nonempty: bool,
-) -> io::Result<()>
+/// Write the definition of column `c`, along with its default value if it is sent in response to
+/// `COM_FIELD_LIST`.
+fn write_column_definition(c: &Column, field_list: bool, w: &mut PacketWriter) -> io::Result<()> {
+    use crate::myc::constants::UTF8_GENERAL_CI;
+    w.write_lenenc_str(b"def")?;
+    w.write_lenenc_str(c.schema.as_deref().unwrap_or("").as_bytes())?;
+    w.write_lenenc_str(c.table.as_bytes())?;
+    w.write_lenenc_str(c.org_table.as_deref().unwrap_or("").as_bytes())?;
+    w.write_lenenc_str(c.column.as_bytes())?;
+    w.write_lenenc_str(c.org_column.as_deref().unwrap_or("").as_bytes())?;
+    w.write_lenenc_int(0xC)?;
+    w.write_u16::<LittleEndian>(c.character_set.unwrap_or(UTF8_GENERAL_CI))?;
+    w.write_u32::<LittleEndian>(c.column_length.unwrap_or(1024))?;
+    w.write_u8(c.coltype as u8)?;
+    w.write_u16::<LittleEndian>(c.colflags.bits())?;
+    w.write_u8(c.decimals.unwrap_or(0))?;
+    w.write_all(&[0x00, 0x00])?; // unused
+    if field_list {
+        match c.default_value {
+            Some(ref default) => {
+                w.write_lenenc_str(default.as_bytes())?;
+            }
+            None => w.write_u8(0xFB)?, // NULL
+        }
+    }
+    w.end_packet();
+    Ok(())
+}
+
+/// Write the definitions of the columns in `i`, followed by an EOF packet if there were any.
+fn write_column_definitions<'a, I>(i: I, w: &mut PacketWriter) -> io::Result<()>
 where
     I: IntoIterator<Item = &'a Column>,
 {
     let mut empty = true;
     for c in i {
-        let c = c.borrow();
-        use crate::myc::constants::UTF8_GENERAL_CI;
-        w.write_lenenc_str(b"def")?;
-        w.write_lenenc_str(b"")?;
-        w.write_lenenc_str(c.table.as_bytes())?;
-        w.write_lenenc_str(b"")?;
-        w.write_lenenc_str(c.column.as_bytes())?;
-        w.write_lenenc_str(b"")?;
-        w.write_lenenc_int(0xC)?;
-        w.write_u16::<LittleEndian>(UTF8_GENERAL_CI)?;
-        w.write_u32::<LittleEndian>(1024)?;
-        w.write_u8(c.coltype as u8)?;
-        w.write_u16::<LittleEndian>(c.colflags.bits())?;
-        w.write_all(&[0x00])?; // decimals
-        w.write_all(&[0x00, 0x00])?; // unused
-        w.end_packet();
+        write_column_definition(c, false, w)?;
         empty = false;
     }
 
-    if empty && only_eof_on_nonempty {
+    if empty {
         Ok(())
     } else {
         write_eof_packet(w, StatusFlags::empty())
     }
 }
 
-pub(crate) fn column_definitions<'a, I>(i: I, w: &mut PacketWriter) -> io::Result<()>
+/// Answer `COM_FIELD_LIST` with the definitions of the columns in `i`, including their default
+/// values.
+pub(crate) fn write_field_list<'a, I>(i: I, w: &mut PacketWriter) -> io::Result<()>
+where
+    I: IntoIterator<Item = &'a Column>,
+{
+    for c in i {
+        write_column_definition(c, true, w)?;
+    }
+    write_eof_packet(w, StatusFlags::empty())
+}
+
+/// Start a resultset by announcing its columns, ending with an EOF packet carrying `status`.
+///
+/// Clients that deprecated EOF packets only get one if `status` has something to tell them, such
+/// as that a cursor was opened.
+pub(crate) fn column_definitions<'a, I>(
+    i: I,
+    status: StatusFlags,
+    w: &mut PacketWriter,
+) -> io::Result<()>
 where
     I: IntoIterator<Item = &'a Column>,
     <I as IntoIterator>::IntoIter: ExactSizeIterator,
@@ -111,5 +214,12 @@ where
     let i = i.into_iter();
     w.write_lenenc_int(i.len() as u64)?;
     w.end_packet();
-    write_column_definitions(i, w, false)
+    for c in i {
+        write_column_definition(c, false, w)?;
+    }
+    if w.deprecate_eof() && status.is_empty() {
+        Ok(())
+    } else {
+        write_eof_packet(w, status)
+    }
 }
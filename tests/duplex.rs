@@ -0,0 +1,2421 @@
+//! Tests that drive `MysqlIntermediary` over an in-memory `tokio::io::duplex` pipe, speaking the
+//! wire protocol by hand rather than going through a client library.
+
+extern crate msql_srv;
+extern crate mysql_common as myc;
+
+use async_trait::async_trait;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+use msql_srv::rsa::pkcs8::DecodePublicKey;
+use msql_srv::rsa::{Oaep, RsaPrivateKey, RsaPublicKey};
+use msql_srv::{
+    AuthPlugin, Authenticator, CapabilityFlags, Column, ConnectionContext, ErrorKind, InitWriter,
+    IntoMysqlError, ListFieldsWriter, MysqlIntermediary, MysqlShim, NativePasswordAuthenticator,
+    ParamParser, ProcessInfo, QueryResultWriter, ServerOptions, SessionStateChange,
+    StatementMetaWriter, Statistics, StatusFlags, Warning, WarningLevel,
+};
+
+struct Client<S = DuplexStream> {
+    stream: S,
+    seq: u8,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    async fn read_packet(&mut self) -> Vec<u8> {
+        let mut header = [0u8; 4];
+        self.stream.read_exact(&mut header).await.unwrap();
+        let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+        self.seq = header[3].wrapping_add(1);
+        let mut data = vec![0; len];
+        self.stream.read_exact(&mut data).await.unwrap();
+        data
+    }
+
+    async fn write_packet(&mut self, data: &[u8]) {
+        let len = (data.len() as u32).to_le_bytes();
+        self.stream
+            .write_all(&[len[0], len[1], len[2], self.seq])
+            .await
+            .unwrap();
+        self.stream.write_all(data).await.unwrap();
+        self.seq = self.seq.wrapping_add(1);
+    }
+
+    async fn command(&mut self, data: &[u8]) {
+        self.seq = 0;
+        self.write_packet(data).await;
+    }
+
+    /// Read the server's initial handshake and log in as `user` without a password.
+    async fn handshake(&mut self, user: &str) {
+        self.read_greeting().await;
+        self.handshake_response(user, &[]).await;
+
+        let ok = self.read_packet().await;
+        assert_eq!(ok[0], 0x00, "expected OK, got {:?}", ok);
+    }
+
+    /// Read the server's initial handshake, and return the scramble it contains.
+    async fn read_greeting(&mut self) -> Vec<u8> {
+        let greeting = self.read_packet().await;
+        assert_eq!(greeting[0], 10);
+        let version_end = greeting.iter().position(|&b| b == 0).unwrap();
+        let data = &greeting[version_end + 1 + 4..];
+        let mut scramble = data[..8].to_vec();
+        scramble.extend(&data[8 + 1 + 2 + 1 + 2 + 2 + 1 + 10..][..12]);
+        scramble
+    }
+
+    async fn handshake_response(&mut self, user: &str, auth_response: &[u8]) {
+        let mut response = Vec::new();
+        // CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION
+        response.extend(&0x0000_8200u32.to_le_bytes());
+        response.extend(&16_777_216u32.to_le_bytes());
+        response.push(0x21);
+        response.extend(&[0; 23]);
+        response.extend(user.as_bytes());
+        response.push(0);
+        response.push(auth_response.len() as u8);
+        response.extend(auth_response);
+        self.write_packet(&response).await;
+    }
+
+    async fn handshake_response_with_plugin(
+        &mut self,
+        user: &str,
+        auth_response: &[u8],
+        plugin: &str,
+    ) {
+        let mut response = Vec::new();
+        // CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH
+        response.extend(&0x0008_8200u32.to_le_bytes());
+        response.extend(&16_777_216u32.to_le_bytes());
+        response.push(0x21);
+        response.extend(&[0; 23]);
+        response.extend(user.as_bytes());
+        response.push(0);
+        response.push(auth_response.len() as u8);
+        response.extend(auth_response);
+        response.extend(plugin.as_bytes());
+        response.push(0);
+        self.write_packet(&response).await;
+    }
+
+    /// Read an `AuthSwitchRequest`, and return the plugin name and data it contains.
+    async fn read_auth_switch_request(&mut self) -> (String, Vec<u8>) {
+        let packet = self.read_packet().await;
+        assert_eq!(
+            packet[0], 0xFE,
+            "expected AuthSwitchRequest, got {:?}",
+            packet
+        );
+        let name_end = packet.iter().position(|&b| b == 0).unwrap();
+        let name = String::from_utf8(packet[1..name_end].to_vec()).unwrap();
+        let mut data = packet[name_end + 1..].to_vec();
+        if data.last() == Some(&0) {
+            data.pop();
+        }
+        (name, data)
+    }
+}
+
+/// Read a text result set, returning its rows as lists of (possibly NULL) values.
+async fn read_rows(c: &mut Client) -> Vec<Vec<Option<String>>> {
+    let cols = c.read_packet().await[0];
+    for _ in 0..cols {
+        c.read_packet().await;
+    }
+    assert_eq!(c.read_packet().await[0], 0xFE);
+    let mut rows = Vec::new();
+    loop {
+        let row = c.read_packet().await;
+        if row[0] == 0xFE && row.len() < 9 {
+            return rows;
+        }
+        let mut values = Vec::new();
+        let mut row = &row[..];
+        while !row.is_empty() {
+            if row[0] == 0xFB {
+                values.push(None);
+                row = &row[1..];
+            } else {
+                let len = row[0] as usize;
+                values.push(Some(String::from_utf8(row[1..=len].to_vec()).unwrap()));
+                row = &row[1 + len..];
+            }
+        }
+        rows.push(values);
+    }
+}
+
+fn duplex_test<B, C, F>(shim: B, c: C)
+where
+    B: MysqlShim + 'static,
+    C: FnOnce(Client) -> F,
+    F: std::future::Future<Output = ()>,
+{
+    duplex_test_with_options(shim, ServerOptions::default(), c)
+}
+
+fn duplex_test_with_options<B, C, F>(shim: B, options: ServerOptions, c: C)
+where
+    B: MysqlShim + 'static,
+    C: FnOnce(Client) -> F,
+    F: std::future::Future<Output = ()>,
+{
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async move {
+        let (client, server) = tokio::io::duplex(4096);
+        let jh = tokio::spawn(async move {
+            MysqlIntermediary::run_with_options(shim, server, options)
+                .await
+                .unwrap_or_else(|_| panic!("run error"))
+        });
+        c(Client {
+            stream: client,
+            seq: 0,
+        })
+        .await;
+        jh.await.unwrap();
+    });
+}
+
+#[test]
+fn it_pings_over_duplex() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    duplex_test(TestingShim, |mut c| async move {
+        c.handshake("jon").await;
+        c.command(&[0x0e]).await; // COM_PING
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_queries_over_duplex() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_query(&mut self, q: &str, w: QueryResultWriter<'_>) -> io::Result<()> {
+            assert_eq!(q, "SELECT a FROM foo");
+            let cols = &[Column {
+                table: String::new(),
+                column: "a".to_owned(),
+                coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
+                colflags: myc::constants::ColumnFlags::empty(),
+                ..Default::default()
+            }];
+            let mut w = w.start(cols).await?;
+            w.write_col(1024i16)?;
+            w.finish().await
+        }
+    }
+
+    duplex_test(TestingShim, |mut c| async move {
+        c.handshake("jon").await;
+        c.command(b"\x03SELECT a FROM foo").await;
+        assert_eq!(c.read_packet().await, vec![1]); // column count
+        let def = c.read_packet().await;
+        assert_eq!(&def[..4], b"\x03def");
+        assert_eq!(c.read_packet().await[0], 0xFE); // EOF after column definitions
+        assert_eq!(c.read_packet().await, b"\x041024".to_vec());
+        assert_eq!(c.read_packet().await[0], 0xFE); // EOF after rows
+        drop(c);
+    });
+}
+
+#[test]
+fn it_rejects_unknown_commands() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    duplex_test(TestingShim, |mut c| async move {
+        c.handshake("jon").await;
+        c.command(&[0x0f]).await; // COM_TIME
+        let err = c.read_packet().await;
+        assert_eq!(err[0], 0xFF);
+        assert_eq!(
+            u16::from_le_bytes([err[1], err[2]]),
+            ErrorKind::ER_UNKNOWN_COM_ERROR as u16
+        );
+        assert_eq!(&err[9..], b"Unknown command");
+        c.command(&[0x0e]).await; // COM_PING
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_rejects_malformed_commands_without_closing_the_connection() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_query(&mut self, _: &str, _: QueryResultWriter<'_>) -> io::Result<()> {
+            panic!("query should have been rejected by the server");
+        }
+    }
+
+    duplex_test(TestingShim, |mut c| async move {
+        c.handshake("jon").await;
+
+        // known commands that are too short to parse
+        c.command(b"\x17\x01").await; // COM_STMT_EXECUTE
+        let err = c.read_packet().await;
+        assert_eq!(err[0], 0xFF);
+        assert_eq!(
+            u16::from_le_bytes([err[1], err[2]]),
+            ErrorKind::ER_MALFORMED_PACKET as u16
+        );
+        // which get no response if the client does not expect one
+        c.command(b"\x19\x01").await; // COM_STMT_CLOSE
+        c.command(b"\x18\x01").await; // COM_STMT_SEND_LONG_DATA
+
+        // COM_STMT_SEND_LONG_DATA for a statement that was never prepared gets no response
+        c.command(b"\x18\x07\x00\x00\x00\x00\x00abc").await;
+
+        // COM_STMT_EXECUTE of a statement that was never prepared
+        c.command(b"\x17\x07\x00\x00\x00\x00\x01\x00\x00\x00").await;
+        let err = c.read_packet().await;
+        assert_eq!(err[0], 0xFF);
+        assert_eq!(
+            u16::from_le_bytes([err[1], err[2]]),
+            ErrorKind::ER_UNKNOWN_STMT_HANDLER as u16
+        );
+        assert_eq!(
+            &err[9..],
+            &b"Unknown prepared statement handler (7) given to mysqld_stmt_execute"[..]
+        );
+
+        for command in &[
+            &b"\x03SELECT '\xff'"[..], // COM_QUERY
+            b"\x03USE \xff",           // COM_QUERY
+            b"\x16SELECT '\xff'",      // COM_STMT_PREPARE
+            b"\x02\xff",               // COM_INIT_DB
+            b"\x04\xff\x00",           // COM_FIELD_LIST
+        ] {
+            c.command(command).await;
+            let err = c.read_packet().await;
+            assert_eq!(err[0], 0xFF, "{:?}", command);
+            assert_eq!(
+                u16::from_le_bytes([err[1], err[2]]),
+                ErrorKind::ER_INVALID_CHARACTER_STRING as u16
+            );
+        }
+
+        c.command(&[0x0e]).await; // COM_PING
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_lets_the_shim_handle_unknown_commands() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_unknown_command(
+            &mut self,
+            command: u8,
+            data: &[u8],
+            w: QueryResultWriter<'_>,
+        ) -> io::Result<()> {
+            assert_eq!(command, 0xFA);
+            assert_eq!(data, b"hello");
+            w.completed(0, 0).await
+        }
+    }
+
+    duplex_test(TestingShim, |mut c| async move {
+        c.handshake("jon").await;
+        c.command(b"\xFAhello").await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_reports_statistics() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    // a registry of its own, so that connections of other tests are not counted
+    let options = ServerOptions::default().with_connection_registry(Default::default());
+    duplex_test_with_options(TestingShim, options, |mut c| async move {
+        c.handshake("jon").await;
+        c.command(&[0x0e]).await; // COM_PING
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x1f]).await; // COM_RESET_CONNECTION
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x0d]).await; // COM_DEBUG
+        assert_eq!(c.read_packet().await[0], 0x00);
+
+        c.command(&[0x09]).await; // COM_STATISTICS
+        let statistics = String::from_utf8(c.read_packet().await).unwrap();
+        assert!(statistics.starts_with("Uptime: "), "{}", statistics);
+        assert!(
+            statistics.contains("  Threads: 1  Questions: 2  Slow queries: 0  "),
+            "{}",
+            statistics
+        );
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_reports_statistics_across_connections_by_default() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async move {
+        let mut clients = Vec::new();
+        let mut servers = Vec::new();
+        for user in &["alice", "bob"] {
+            let (client, server) = tokio::io::duplex(4096);
+            servers.push(tokio::spawn(MysqlIntermediary::run_on(TestingShim, server)));
+            let mut c = Client {
+                stream: client,
+                seq: 0,
+            };
+            c.handshake(user).await;
+            clients.push(c);
+        }
+
+        clients[1].command(&[0x09]).await; // COM_STATISTICS
+        let statistics = String::from_utf8(clients[1].read_packet().await).unwrap();
+        let threads: usize = statistics
+            .split("  ")
+            .find_map(|counter| counter.strip_prefix("Threads: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        // other tests may have connections in the same registry
+        assert!(threads >= 2, "{}", statistics);
+
+        for mut c in clients {
+            c.command(&[0x01]).await; // COM_QUIT
+        }
+        for server in servers {
+            server.await.unwrap().unwrap();
+        }
+    });
+}
+
+#[test]
+fn it_lets_the_shim_report_statistics() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_statistics(&mut self, statistics: Statistics) -> String {
+            format!("Threads: {}  Tables: 3", statistics.threads)
+        }
+    }
+
+    let options = ServerOptions::default().with_connection_registry(Default::default());
+    duplex_test_with_options(TestingShim, options, |mut c| async move {
+        c.handshake("jon").await;
+        c.command(&[0x09]).await; // COM_STATISTICS
+        assert_eq!(c.read_packet().await, b"Threads: 1  Tables: 3");
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_falls_back_to_the_default_statistics() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_statistics(&mut self, _: Statistics) -> String {
+            String::new()
+        }
+    }
+
+    duplex_test(TestingShim, |mut c| async move {
+        c.handshake("jon").await;
+        c.command(&[0x09]).await; // COM_STATISTICS
+        let statistics = String::from_utf8(c.read_packet().await).unwrap();
+        assert!(statistics.starts_with("Uptime: "), "{}", statistics);
+        c.command(&[0x0e]).await; // COM_PING
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_splits_multi_statement_queries() {
+    use std::sync::{Arc, Mutex};
+
+    struct TestingShim(Arc<Mutex<Vec<String>>>);
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_query(&mut self, q: &str, w: QueryResultWriter<'_>) -> io::Result<()> {
+            self.0.lock().unwrap().push(q.to_owned());
+            if q == "FAIL" {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "failed"));
+            }
+            w.completed(1, 0).await
+        }
+    }
+
+    fn ok_status(ok: &[u8]) -> StatusFlags {
+        assert_eq!(ok[0], 0x00, "expected OK, got {:?}", ok);
+        StatusFlags::from_bits_truncate(u16::from_le_bytes([ok[3], ok[4]]))
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let shim = TestingShim(events.clone());
+    let options = ServerOptions::default().with_multi_statements(true);
+    duplex_test_with_options(shim, options, |mut c| async move {
+        c.read_greeting().await;
+        let mut response = Vec::new();
+        // CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_MULTI_STATEMENTS
+        response.extend(&0x0001_8200u32.to_le_bytes());
+        response.extend(&16_777_216u32.to_le_bytes());
+        response.push(0x21);
+        response.extend(&[0; 23]);
+        response.extend(b"jon\0\0");
+        c.write_packet(&response).await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+
+        c.command(b"\x03UPDATE a; UPDATE 'b;c'; FAIL; UPDATE d")
+            .await;
+        let more = StatusFlags::SERVER_MORE_RESULTS_EXISTS;
+        assert!(ok_status(&c.read_packet().await).contains(more));
+        assert!(ok_status(&c.read_packet().await).contains(more));
+        assert_eq!(c.read_packet().await[0], 0xFF);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["UPDATE a", "UPDATE 'b;c'", "FAIL"]
+        );
+
+        c.command(b"\x1b\x01\x00").await; // COM_SET_OPTION MYSQL_OPTION_MULTI_STATEMENTS_OFF
+        assert_eq!(c.read_packet().await[0], 0xFE);
+        c.command(b"\x03UPDATE a; UPDATE b").await;
+        assert!(!ok_status(&c.read_packet().await).contains(more));
+        assert_eq!(events.lock().unwrap()[3], "UPDATE a; UPDATE b");
+
+        c.command(b"\x1b\x05\x00").await;
+        assert_eq!(c.read_packet().await[0], 0xFF);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_lists_fields() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_list_fields(
+            &mut self,
+            table: &str,
+            wildcard: &str,
+            w: ListFieldsWriter<'_>,
+        ) -> io::Result<()> {
+            if table != "users" {
+                let msg = format!("Table 'db.{}' doesn't exist", table);
+                return w.error(ErrorKind::ER_NO_SUCH_TABLE, msg.as_bytes()).await;
+            }
+            let cols: Vec<_> = ["id", "name"]
+                .iter()
+                .filter(|col| col.starts_with(wildcard.trim_end_matches('%')))
+                .map(|col| Column {
+                    table: table.to_owned(),
+                    column: col.to_string(),
+                    coltype: myc::constants::ColumnType::MYSQL_TYPE_VAR_STRING,
+                    colflags: myc::constants::ColumnFlags::empty(),
+                    ..Default::default()
+                })
+                .collect();
+            w.reply(&cols)
+        }
+    }
+
+    fn column_name(def: &[u8]) -> String {
+        // catalog, schema, table, original table, then the name
+        let mut def = def;
+        for _ in 0..4 {
+            def = &def[1 + def[0] as usize..];
+        }
+        String::from_utf8(def[1..=def[0] as usize].to_vec()).unwrap()
+    }
+
+    duplex_test(TestingShim, |mut c| async move {
+        c.handshake("jon").await;
+
+        c.command(b"\x04users\0").await; // COM_FIELD_LIST
+        assert_eq!(column_name(&c.read_packet().await), "id");
+        assert_eq!(column_name(&c.read_packet().await), "name");
+        assert_eq!(c.read_packet().await[0], 0xFE);
+
+        c.command(b"\x04users\0na%").await;
+        assert_eq!(column_name(&c.read_packet().await), "name");
+        assert_eq!(c.read_packet().await[0], 0xFE);
+
+        c.command(b"\x04orders\0").await;
+        let err = c.read_packet().await;
+        assert_eq!(err[0], 0xFF);
+        assert_eq!(
+            u16::from_le_bytes([err[1], err[2]]),
+            ErrorKind::ER_NO_SUCH_TABLE as u16
+        );
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_sends_column_metadata() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_prepare(&mut self, _: &str, info: StatementMetaWriter<'_>) -> io::Result<()> {
+            info.reply(1, &[], &columns())
+        }
+
+        async fn on_query(&mut self, _: &str, w: QueryResultWriter<'_>) -> io::Result<()> {
+            let cols = columns();
+            w.start(&cols).await?.finish().await
+        }
+
+        async fn on_list_fields(
+            &mut self,
+            _: &str,
+            _: &str,
+            w: ListFieldsWriter<'_>,
+        ) -> io::Result<()> {
+            let cols = columns();
+            let plain = Column {
+                column: "note".to_owned(),
+                ..Default::default()
+            };
+            w.reply(&[cols[0].clone(), plain])
+        }
+    }
+
+    fn columns() -> [Column; 1] {
+        [Column {
+            table: "o".to_owned(),
+            column: "total".to_owned(),
+            coltype: myc::constants::ColumnType::MYSQL_TYPE_NEWDECIMAL,
+            colflags: myc::constants::ColumnFlags::NOT_NULL_FLAG,
+            schema: Some("shop".to_owned()),
+            org_table: Some("orders".to_owned()),
+            org_column: Some("amount".to_owned()),
+            character_set: Some(63),
+            column_length: Some(12),
+            decimals: Some(2),
+            default_value: Some("0.00".to_owned()),
+        }]
+    }
+
+    let mut definition = b"\x03def\x04shop\x01o\x06orders\x05total\x06amount\x0c".to_vec();
+    definition.extend(&[63, 0]); // binary
+    definition.extend(&[12, 0, 0, 0]); // length
+    definition.push(0xf6); // MYSQL_TYPE_NEWDECIMAL
+    definition.extend(&[0x01, 0x00]); // NOT_NULL_FLAG
+    definition.push(2); // decimals
+    definition.extend(&[0, 0]);
+
+    duplex_test(TestingShim, |mut c| async move {
+        c.handshake("jon").await;
+
+        c.command(b"\x03SELECT total FROM shop.orders o").await;
+        assert_eq!(c.read_packet().await, vec![1]);
+        assert_eq!(c.read_packet().await, definition);
+        assert_eq!(c.read_packet().await[0], 0xFE);
+        assert_eq!(c.read_packet().await[0], 0xFE);
+
+        c.command(b"\x16SELECT total FROM shop.orders o").await; // COM_STMT_PREPARE
+        assert_eq!(c.read_packet().await[0], 0x00);
+        assert_eq!(c.read_packet().await, definition);
+        assert_eq!(c.read_packet().await[0], 0xFE);
+
+        // only COM_FIELD_LIST gets the default values, with NULL for columns without one
+        c.command(b"\x04orders\0").await;
+        let mut with_default = definition.clone();
+        with_default.extend(b"\x040.00");
+        assert_eq!(c.read_packet().await, with_default);
+        assert_eq!(c.read_packet().await.last(), Some(&0xFB));
+        assert_eq!(c.read_packet().await[0], 0xFE);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_gives_the_shim_a_connection_context() {
+    use std::sync::{Arc, Mutex};
+
+    struct TestingShim(Arc<Mutex<Option<ConnectionContext>>>);
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_connect(&mut self, context: &ConnectionContext) {
+            *self.0.lock().unwrap() = Some(context.clone());
+        }
+    }
+
+    let seen = Arc::new(Mutex::new(None));
+    duplex_test(TestingShim(seen.clone()), |mut c| async move {
+        // like libmysql, only use the capabilities the server advertises
+        let greeting = c.read_packet().await;
+        let version_end = greeting.iter().position(|&b| b == 0).unwrap();
+        let data = &greeting[version_end + 1 + 4 + 8 + 1..];
+        let advertised = CapabilityFlags::from_bits_truncate(
+            u32::from(u16::from_le_bytes([data[0], data[1]]))
+                | u32::from(u16::from_le_bytes([data[5], data[6]])) << 16,
+        );
+        let wanted = CapabilityFlags::CLIENT_PROTOCOL_41
+            | CapabilityFlags::CLIENT_SECURE_CONNECTION
+            | CapabilityFlags::CLIENT_CONNECT_WITH_DB
+            | CapabilityFlags::CLIENT_PLUGIN_AUTH
+            | CapabilityFlags::CLIENT_CONNECT_ATTRS;
+        assert!(advertised.contains(wanted), "{:?}", advertised);
+
+        let mut response = Vec::new();
+        response.extend(&(advertised & wanted).bits().to_le_bytes());
+        response.extend(&1_048_576u32.to_le_bytes());
+        response.push(0x2d);
+        response.extend(&[0; 23]);
+        response.extend(b"jon\0");
+        response.push(0);
+        response.extend(b"tenant_a\0");
+        response.extend(b"mysql_native_password\0");
+        let attrs = b"\x0c_client_name\x08libmysql\x0cprogram_name\x05mysql";
+        response.push(attrs.len() as u8);
+        response.extend(&attrs[..]);
+        c.write_packet(&response).await;
+
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+
+    let context = seen.lock().unwrap().take().unwrap();
+    assert_eq!(context.username, "jon");
+    assert_eq!(context.database.as_deref(), Some("tenant_a"));
+    assert_eq!(context.attributes["_client_name"], "libmysql");
+    assert_eq!(context.attributes["program_name"], "mysql");
+    assert_eq!(context.attributes.len(), 2);
+    assert!(context
+        .capabilities
+        .contains(CapabilityFlags::CLIENT_CONNECT_WITH_DB));
+    assert_eq!(context.collation, 0x2d);
+    assert_eq!(context.max_packet_size, 1_048_576);
+    assert_eq!(context.peer_addr, None);
+    assert!(!context.secure);
+}
+
+#[test]
+fn it_answers_session_connect_attrs() {
+    use std::sync::{Arc, Mutex};
+
+    struct TestingShim(Arc<Mutex<Vec<String>>>);
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_query(&mut self, q: &str, w: QueryResultWriter<'_>) -> io::Result<()> {
+            self.0.lock().unwrap().push(q.to_owned());
+            w.completed(0, 0).await
+        }
+    }
+
+    let queries = Arc::new(Mutex::new(Vec::new()));
+    let shim = TestingShim(queries.clone());
+    duplex_test(shim, |mut c| async move {
+        let greeting = c.read_packet().await;
+        let version_end = greeting.iter().position(|&b| b == 0).unwrap();
+        let caps = &greeting[version_end + 1 + 4 + 9..];
+        let caps = &[caps[0], caps[1], caps[5], caps[6]];
+        // CLIENT_CONNECT_ATTRS and CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA
+        assert_eq!(u32::from_le_bytes(*caps) & 0x0030_0000, 0x0030_0000);
+
+        let mut response = Vec::new();
+        // CLIENT_PROTOCOL_41 | CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA | CLIENT_CONNECT_ATTRS
+        response.extend(&0x0030_0200u32.to_le_bytes());
+        response.extend(&16_777_216u32.to_le_bytes());
+        response.push(0x21);
+        response.extend(&[0; 23]);
+        response.extend(b"jon\0");
+        response.push(0);
+        let attrs = b"\x0c_client_name\x08libmysql\x04_pid\x0242";
+        response.push(attrs.len() as u8);
+        response.extend(&attrs[..]);
+        c.write_packet(&response).await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+
+        c.command(b"\x03SELECT * FROM performance_schema.session_connect_attrs")
+            .await;
+        assert_eq!(c.read_packet().await, vec![4]);
+        for _ in 0..4 {
+            c.read_packet().await;
+        }
+        assert_eq!(c.read_packet().await[0], 0xFE);
+        // each row starts with the connection id
+        let row = c.read_packet().await;
+        assert_eq!(
+            &row[1 + row[0] as usize..],
+            b"\x0c_client_name\x08libmysql\x010"
+        );
+        let row = c.read_packet().await;
+        assert_eq!(&row[1 + row[0] as usize..], b"\x04_pid\x0242\x011");
+        assert_eq!(c.read_packet().await[0], 0xFE);
+
+        // the server cannot project or filter the attributes, so it leaves such queries alone
+        let q = "SELECT ATTR_VALUE FROM performance_schema.session_connect_attrs \
+                 WHERE ATTR_NAME = '_client_name'";
+        c.command(format!("\x03{}", q).as_bytes()).await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        assert_eq!(*queries.lock().unwrap(), vec![q.to_owned()]);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_advertises_the_configured_identity() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    let options = ServerOptions::default()
+        // a NUL byte would end the version early in the handshake
+        .with_server_version("5.5.5-10.6.12-Maria\0DB")
+        .with_collation(45)
+        .with_capabilities(
+            CapabilityFlags::CLIENT_PROTOCOL_41
+                | CapabilityFlags::CLIENT_SECURE_CONNECTION
+                | CapabilityFlags::CLIENT_PLUGIN_AUTH,
+        )
+        .with_status_flags(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+
+    duplex_test_with_options(TestingShim, options, |mut c| async move {
+        let greeting = c.read_packet().await;
+        assert_eq!(&greeting[1..23], b"5.5.5-10.6.12-MariaDB\0");
+        let rest = &greeting[23 + 4 + 9..];
+        let caps = u32::from_le_bytes([rest[0], rest[1], rest[5], rest[6]]);
+        assert_eq!(caps, 0x0008_8200);
+        assert_eq!(rest[2], 45);
+        assert_eq!(u16::from_le_bytes([rest[3], rest[4]]), 0x0002);
+
+        c.handshake_response("jon", &[]).await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_tracks_connections_in_the_registry() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_init(&mut self, _: &str, w: InitWriter<'_>) -> io::Result<()> {
+            w.ok()
+        }
+    }
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async move {
+        let options = ServerOptions::default().with_connection_registry(Default::default());
+        let registry = options.connection_registry();
+        let mut clients = Vec::new();
+        let mut servers = Vec::new();
+        for user in &["alice", "bob"] {
+            let (client, server) = tokio::io::duplex(4096);
+            let options = options.clone();
+            servers.push(tokio::spawn(async move {
+                MysqlIntermediary::run_with_options(TestingShim, server, options)
+                    .await
+                    .unwrap()
+            }));
+            let mut c = Client {
+                stream: client,
+                seq: 0,
+            };
+            c.handshake(user).await;
+            clients.push(c);
+        }
+
+        let processes = registry.processes();
+        assert_eq!(processes.len(), 2);
+        assert!(processes[0].id < processes[1].id);
+        assert_eq!(processes[0].user, "alice");
+        assert_eq!(processes[1].user, "bob");
+        assert!(processes.iter().all(|p| p.command == "Sleep"));
+
+        let alice = &mut clients[0];
+        alice.command(b"\x02shop").await; // COM_INIT_DB
+        assert_eq!(alice.read_packet().await[0], 0x00);
+        alice.command(b"\x03SELECT CONNECTION_ID()").await;
+        let rows = read_rows(alice).await;
+        assert_eq!(rows, vec![vec![Some(processes[0].id.to_string())]]);
+
+        // users only see their own connections
+        let bob = &mut clients[1];
+        bob.command(b"\x03SHOW FULL PROCESSLIST").await;
+        let rows = read_rows(bob).await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][0], Some(processes[1].id.to_string()));
+        assert_eq!(rows[0][1].as_deref(), Some("bob"));
+        assert_eq!(rows[0][3], None);
+        assert_eq!(rows[0][4].as_deref(), Some("Query"));
+        assert_eq!(rows[0][7].as_deref(), Some("SHOW FULL PROCESSLIST"));
+
+        let alice = &mut clients[0];
+        alice.command(b"\x03SHOW PROCESSLIST").await;
+        let rows = read_rows(alice).await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][1].as_deref(), Some("alice"));
+        assert_eq!(rows[0][3].as_deref(), Some("shop"));
+
+        for mut c in clients {
+            c.command(&[0x01]).await; // COM_QUIT
+        }
+        for server in servers {
+            server.await.unwrap();
+        }
+        assert!(registry.processes().is_empty());
+    });
+}
+
+#[test]
+fn it_shares_the_default_registry_between_connections() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        fn can_access_process(&self, _: &ConnectionContext, _: &ProcessInfo) -> bool {
+            // like a user with the PROCESS privilege
+            true
+        }
+    }
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async move {
+        let mut clients = Vec::new();
+        let mut servers = Vec::new();
+        let mut ids = Vec::new();
+        for user in &["alice", "bob"] {
+            let (client, server) = tokio::io::duplex(4096);
+            servers.push(tokio::spawn(MysqlIntermediary::run_on(TestingShim, server)));
+            let mut c = Client {
+                stream: client,
+                seq: 0,
+            };
+            c.handshake(user).await;
+            c.command(b"\x03SELECT CONNECTION_ID()").await;
+            ids.push(read_rows(&mut c).await[0][0].clone().unwrap());
+            clients.push(c);
+        }
+
+        // other tests may have connections in the same registry
+        clients[0].command(b"\x03SHOW PROCESSLIST").await;
+        let rows = read_rows(&mut clients[0]).await;
+        for (id, user) in ids.iter().zip(&["alice", "bob"]) {
+            let row = rows.iter().find(|row| row[0].as_ref() == Some(id));
+            assert_eq!(row.unwrap()[1].as_deref(), Some(*user));
+        }
+
+        for mut c in clients {
+            c.command(&[0x01]).await; // COM_QUIT
+        }
+        for server in servers {
+            server.await.unwrap().unwrap();
+        }
+    });
+}
+
+#[test]
+fn it_kills_queries_and_connections() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_query(&mut self, q: &str, w: QueryResultWriter<'_>) -> io::Result<()> {
+            if q == "SELECT SLEEP(1000)" {
+                std::future::pending::<()>().await;
+            }
+            w.completed(0, 0).await
+        }
+
+        fn can_access_process(&self, _: &ConnectionContext, _: &ProcessInfo) -> bool {
+            // every user may kill every connection
+            true
+        }
+    }
+
+    fn error_code(packet: &[u8]) -> u16 {
+        assert_eq!(packet[0], 0xFF, "expected ERR, got {:?}", packet);
+        u16::from_le_bytes([packet[1], packet[2]])
+    }
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async move {
+        let options = ServerOptions::default().with_connection_registry(Default::default());
+        let registry = options.connection_registry();
+        let mut clients = Vec::new();
+        let mut servers = Vec::new();
+        for user in &["alice", "bob"] {
+            let (client, server) = tokio::io::duplex(4096);
+            let options = options.clone();
+            servers.push(tokio::spawn(async move {
+                MysqlIntermediary::run_with_options(TestingShim, server, options)
+                    .await
+                    .unwrap()
+            }));
+            let mut c = Client {
+                stream: client,
+                seq: 0,
+            };
+            c.handshake(user).await;
+            clients.push(c);
+        }
+        let alice_id = registry.processes()[0].id;
+        let (alice, bob) = clients.split_at_mut(1);
+        let (alice, bob) = (&mut alice[0], &mut bob[0]);
+
+        alice.command(b"\x03SELECT SLEEP(1000)").await;
+        while registry.process(alice_id).unwrap().command != "Query" {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        bob.command(format!("\x03KILL QUERY {}", alice_id).as_bytes())
+            .await;
+        assert_eq!(bob.read_packet().await[0], 0x00);
+        assert_eq!(
+            error_code(&alice.read_packet().await),
+            ErrorKind::ER_QUERY_INTERRUPTED as u16
+        );
+
+        // the connection survives having its query killed
+        alice.command(b"\x03SELECT 1").await;
+        assert_eq!(alice.read_packet().await[0], 0x00);
+
+        bob.command(b"\x03KILL 4294967295").await;
+        assert_eq!(
+            error_code(&bob.read_packet().await),
+            ErrorKind::ER_NO_SUCH_THREAD as u16
+        );
+
+        // COM_PROCESS_KILL
+        let mut kill = vec![0x0C];
+        kill.extend(&alice_id.to_le_bytes());
+        bob.command(&kill).await;
+        assert_eq!(bob.read_packet().await[0], 0x00);
+        let mut buf = [0; 1];
+        assert_eq!(alice.stream.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(registry.processes().len(), 1);
+
+        bob.command(&[0x01]).await; // COM_QUIT
+        for server in servers {
+            server.await.unwrap();
+        }
+    });
+}
+
+#[test]
+fn it_kills_across_connections_by_default() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_query(&mut self, q: &str, w: QueryResultWriter<'_>) -> io::Result<()> {
+            if q == "SELECT SLEEP(1000)" {
+                std::future::pending::<()>().await;
+            }
+            w.completed(0, 0).await
+        }
+    }
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async move {
+        let mut clients = Vec::new();
+        let mut servers = Vec::new();
+        for user in &["alice", "bob", "alice"] {
+            let (client, server) = tokio::io::duplex(4096);
+            servers.push(tokio::spawn(MysqlIntermediary::run_on(TestingShim, server)));
+            let mut c = Client {
+                stream: client,
+                seq: 0,
+            };
+            c.handshake(user).await;
+            clients.push(c);
+        }
+        let mut other_alice = clients.pop().unwrap();
+        let mut bob = clients.pop().unwrap();
+        let mut alice = clients.pop().unwrap();
+        alice.command(b"\x03SELECT CONNECTION_ID()").await;
+        let alice_id: u32 = read_rows(&mut alice).await[0][0]
+            .as_ref()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        alice.command(b"\x03SELECT SLEEP(1000)").await;
+        let registry = ServerOptions::default().connection_registry();
+        while registry.process(alice_id).unwrap().command != "Query" {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+
+        // other users may not kill alice's connections
+        let mut kill = vec![0x0C]; // COM_PROCESS_KILL
+        kill.extend(&alice_id.to_le_bytes());
+        for command in &[format!("\x03KILL QUERY {}", alice_id).into_bytes(), kill] {
+            bob.command(command).await;
+            let packet = bob.read_packet().await;
+            assert_eq!(packet[0], 0xFF);
+            assert_eq!(
+                u16::from_le_bytes([packet[1], packet[2]]),
+                ErrorKind::ER_KILL_DENIED_ERROR as u16
+            );
+            assert_eq!(
+                &packet[9..],
+                format!("You are not owner of thread {}", alice_id).as_bytes()
+            );
+        }
+        assert_eq!(registry.process(alice_id).unwrap().command, "Query");
+
+        // but alice may, from another connection
+        other_alice
+            .command(format!("\x03KILL QUERY {}", alice_id).as_bytes())
+            .await;
+        assert_eq!(other_alice.read_packet().await[0], 0x00);
+        let packet = alice.read_packet().await;
+        assert_eq!(packet[0], 0xFF);
+        assert_eq!(
+            u16::from_le_bytes([packet[1], packet[2]]),
+            ErrorKind::ER_QUERY_INTERRUPTED as u16
+        );
+
+        other_alice
+            .command(format!("\x03KILL {}", alice_id).as_bytes())
+            .await;
+        assert_eq!(other_alice.read_packet().await[0], 0x00);
+        let mut buf = [0; 1];
+        assert_eq!(alice.stream.read(&mut buf).await.unwrap(), 0);
+
+        for mut c in [bob, other_alice] {
+            c.command(&[0x01]).await; // COM_QUIT
+        }
+        for server in servers {
+            server.await.unwrap().unwrap();
+        }
+    });
+}
+
+#[test]
+fn it_reports_shim_errors_to_the_client() {
+    #[derive(Debug)]
+    enum TestError {
+        Io(io::Error),
+        NoSuchTable(String),
+    }
+
+    impl From<io::Error> for TestError {
+        fn from(e: io::Error) -> Self {
+            TestError::Io(e)
+        }
+    }
+
+    impl IntoMysqlError for TestError {
+        fn into_mysql_error(self) -> Result<(ErrorKind, String), Self> {
+            match self {
+                TestError::Io(e) => e.into_mysql_error().map_err(TestError::Io),
+                TestError::NoSuchTable(table) => Ok((
+                    ErrorKind::ER_NO_SUCH_TABLE,
+                    format!("Table '{}' doesn't exist", table),
+                )),
+            }
+        }
+    }
+
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = TestError;
+
+        async fn on_query(&mut self, q: &str, w: QueryResultWriter<'_>) -> Result<(), TestError> {
+            match q {
+                "SELECT a FROM foo" => {
+                    let cols = [Column {
+                        table: "foo".to_owned(),
+                        column: "a".to_owned(),
+                        coltype: myc::constants::ColumnType::MYSQL_TYPE_LONG,
+                        colflags: myc::constants::ColumnFlags::empty(),
+                        ..Default::default()
+                    }];
+                    let mut w = w.start(&cols).await?;
+                    w.write_col(1)?;
+                    // fail halfway through; what was written so far is never sent
+                    Err(TestError::NoSuchTable("foo".to_owned()))
+                }
+                "SELECT a FROM bar" => {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, "bar is broken").into())
+                }
+                "SELECT a FROM baz" => Err(io::Error::from(io::ErrorKind::ConnectionReset).into()),
+                _ => Ok(w.completed(0, 0).await?),
+            }
+        }
+    }
+
+    fn error(packet: &[u8]) -> (u16, String) {
+        assert_eq!(packet[0], 0xFF, "expected ERR, got {:?}", packet);
+        (
+            u16::from_le_bytes([packet[1], packet[2]]),
+            String::from_utf8(packet[9..].to_vec()).unwrap(),
+        )
+    }
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async move {
+        let (client, server) = tokio::io::duplex(4096);
+        let jh = tokio::spawn(MysqlIntermediary::run_on(TestingShim, server));
+        let mut c = Client {
+            stream: client,
+            seq: 0,
+        };
+        c.handshake("jon").await;
+
+        c.command(b"\x03SELECT a FROM foo").await;
+        assert_eq!(
+            error(&c.read_packet().await),
+            (
+                ErrorKind::ER_NO_SUCH_TABLE as u16,
+                "Table 'foo' doesn't exist".to_owned()
+            )
+        );
+        c.command(b"\x03SELECT a FROM bar").await;
+        assert_eq!(
+            error(&c.read_packet().await),
+            (
+                ErrorKind::ER_UNKNOWN_ERROR as u16,
+                "bar is broken".to_owned()
+            )
+        );
+
+        // the connection is still usable
+        c.command(b"\x03SELECT 1").await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+
+        // but errors about the connection itself close it
+        c.command(b"\x03SELECT a FROM baz").await;
+        let mut buf = [0; 1];
+        assert_eq!(c.stream.read(&mut buf).await.unwrap(), 0);
+        match jh.await.unwrap() {
+            Err(TestError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+            r => panic!("expected the connection to fail, got {:?}", r),
+        }
+    });
+}
+
+fn native_password_options() -> ServerOptions {
+    let mut auth = NativePasswordAuthenticator::new();
+    auth.add_user("jon", b"hunter2");
+    auth.add_user("guest", b"");
+    ServerOptions::default().with_authenticator(auth)
+}
+
+#[test]
+fn it_accepts_native_password() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    duplex_test_with_options(TestingShim, native_password_options(), |mut c| async move {
+        let scramble = c.read_greeting().await;
+        let token = myc::scramble::scramble_native(&scramble, b"hunter2").unwrap();
+        c.handshake_response("jon", &token).await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+
+    duplex_test_with_options(TestingShim, native_password_options(), |mut c| async move {
+        c.handshake("guest").await;
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_denies_wrong_password() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    for &(user, password) in &[
+        ("jon", &b"hunter3"[..]),
+        ("jon", b""),
+        ("nobody", b"hunter2"),
+    ] {
+        duplex_test_with_options(TestingShim, native_password_options(), |mut c| async move {
+            let scramble = c.read_greeting().await;
+            let token = myc::scramble::scramble_native(&scramble, password)
+                .map(|t| t.to_vec())
+                .unwrap_or_default();
+            c.handshake_response(user, &token).await;
+            let err = c.read_packet().await;
+            assert_eq!(err[0], 0xFF);
+            assert_eq!(
+                u16::from_le_bytes([err[1], err[2]]),
+                ErrorKind::ER_ACCESS_DENIED_ERROR as u16
+            );
+            let using_password = if password.is_empty() { "NO" } else { "YES" };
+            assert_eq!(
+                String::from_utf8_lossy(&err[9..]),
+                format!(
+                    "Access denied for user '{}'@'localhost' (using password: {})",
+                    user, using_password
+                )
+            );
+        });
+    }
+}
+
+#[test]
+fn it_changes_users() {
+    use std::sync::{Arc, Mutex};
+
+    struct TestingShim(Arc<Mutex<Vec<String>>>);
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_prepare(&mut self, _: &str, info: StatementMetaWriter<'_>) -> io::Result<()> {
+            info.reply(1, &[], &[])
+        }
+
+        async fn on_close(&mut self, stmt: u32) {
+            self.0.lock().unwrap().push(format!("close {}", stmt));
+        }
+
+        async fn on_change_user(&mut self, context: &ConnectionContext) {
+            self.0.lock().unwrap().push(format!(
+                "change {} {:?}",
+                context.username, context.database
+            ));
+        }
+    }
+
+    fn change_user(user: &str, auth_response: &[u8], database: &str) -> Vec<u8> {
+        let mut data = vec![0x11];
+        data.extend(user.as_bytes());
+        data.push(0);
+        data.push(auth_response.len() as u8);
+        data.extend(auth_response);
+        data.extend(database.as_bytes());
+        data.push(0);
+        data.extend(&[0x21, 0x00]);
+        data
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let shim = TestingShim(events.clone());
+    duplex_test_with_options(shim, native_password_options(), |mut c| async move {
+        let scramble = c.read_greeting().await;
+        let token = myc::scramble::scramble_native(&scramble, b"hunter2").unwrap();
+        c.handshake_response("jon", &token).await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+
+        c.command(b"\x16SELECT 1").await; // COM_STMT_PREPARE
+        assert_eq!(c.read_packet().await[0], 0x00);
+
+        c.command(&change_user("guest", &[], "shop")).await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "close 1".to_owned(),
+                "change guest Some(\"shop\")".to_owned()
+            ]
+        );
+
+        c.command(&change_user("jon", &token, "")).await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        assert_eq!(events.lock().unwrap()[2], "change jon None");
+
+        // a failed change of user closes the connection
+        c.command(&change_user("jon", b"hunter3", "")).await;
+        let err = c.read_packet().await;
+        assert_eq!(err[0], 0xFF);
+        assert_eq!(
+            u16::from_le_bytes([err[1], err[2]]),
+            ErrorKind::ER_ACCESS_DENIED_ERROR as u16
+        );
+        let mut buf = [0; 1];
+        assert_eq!(c.stream.read(&mut buf).await.unwrap(), 0);
+    });
+}
+
+#[test]
+fn it_resets_connections() {
+    use std::sync::{Arc, Mutex};
+
+    struct TestingShim(Arc<Mutex<Vec<String>>>);
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_prepare(&mut self, _: &str, info: StatementMetaWriter<'_>) -> io::Result<()> {
+            info.reply(1, &[], &[])
+        }
+
+        async fn on_close(&mut self, stmt: u32) {
+            self.0.lock().unwrap().push(format!("close {}", stmt));
+        }
+
+        async fn on_reset(&mut self) {
+            self.0.lock().unwrap().push("reset".to_owned());
+        }
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let shim = TestingShim(events.clone());
+    duplex_test(shim, |mut c| async move {
+        c.handshake("jon").await;
+        c.command(b"\x16SELECT 1").await; // COM_STMT_PREPARE
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(b"\x18\x01\x00\x00\x00\x00\x00abc").await; // COM_STMT_SEND_LONG_DATA
+
+        c.command(&[0x1f]).await; // COM_RESET_CONNECTION
+        assert_eq!(c.read_packet().await[0], 0x00);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["close 1".to_owned(), "reset".to_owned()]
+        );
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_resets_statements() {
+    use std::sync::{Arc, Mutex};
+
+    struct TestingShim(Arc<Mutex<Vec<String>>>);
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_prepare(&mut self, _: &str, info: StatementMetaWriter<'_>) -> io::Result<()> {
+            let params = [Column {
+                table: String::new(),
+                column: "?".to_owned(),
+                coltype: myc::constants::ColumnType::MYSQL_TYPE_VAR_STRING,
+                colflags: myc::constants::ColumnFlags::empty(),
+                ..Default::default()
+            }];
+            info.reply(1, &params, &[])
+        }
+
+        async fn on_execute(
+            &mut self,
+            _: u32,
+            params: ParamParser<'_>,
+            w: QueryResultWriter<'_>,
+        ) -> io::Result<()> {
+            for param in params.into_iter() {
+                let value: &str = param.value.into();
+                self.0.lock().unwrap().push(value.to_owned());
+            }
+            w.completed(0, 0).await
+        }
+
+        async fn on_reset_stmt(&mut self, stmt: u32) {
+            self.0.lock().unwrap().push(format!("reset {}", stmt));
+        }
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let shim = TestingShim(events.clone());
+    duplex_test(shim, |mut c| async move {
+        c.handshake("jon").await;
+        c.command(b"\x16SELECT ?").await; // COM_STMT_PREPARE
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.read_packet().await; // parameter definition
+        assert_eq!(c.read_packet().await[0], 0xFE);
+
+        c.command(b"\x18\x01\x00\x00\x00\x00\x00abc").await; // COM_STMT_SEND_LONG_DATA
+        c.command(b"\x1a\x01\x00\x00\x00").await; // COM_STMT_RESET
+        assert_eq!(c.read_packet().await[0], 0x00);
+
+        // COM_STMT_EXECUTE, with the parameter sent inline
+        c.command(b"\x17\x01\x00\x00\x00\x00\x01\x00\x00\x00\x00\x01\xfd\x00\x03xyz")
+            .await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["reset 1".to_owned(), "xyz".to_owned()]
+        );
+
+        // the types bound by the last execution survive a reset
+        c.command(b"\x1a\x01\x00\x00\x00").await; // COM_STMT_RESET
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(b"\x17\x01\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x03uvw")
+            .await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        assert_eq!(events.lock().unwrap()[2..], ["reset 1", "uvw"]);
+
+        // but a statement that was never executed has no types to fall back on
+        c.command(b"\x16SELECT ?").await; // COM_STMT_PREPARE
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.read_packet().await; // parameter definition
+        assert_eq!(c.read_packet().await[0], 0xFE);
+        c.command(b"\x17\x01\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x03uvw")
+            .await;
+        let err = c.read_packet().await;
+        assert_eq!(err[0], 0xFF);
+        assert_eq!(
+            u16::from_le_bytes([err[1], err[2]]),
+            ErrorKind::ER_WRONG_ARGUMENTS as u16
+        );
+
+        c.command(b"\x1a\x02\x00\x00\x00").await; // COM_STMT_RESET
+        let err = c.read_packet().await;
+        assert_eq!(err[0], 0xFF);
+        assert_eq!(
+            u16::from_le_bytes([err[1], err[2]]),
+            ErrorKind::ER_UNKNOWN_STMT_HANDLER as u16
+        );
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_fetches_rows_through_cursors() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_prepare(&mut self, _: &str, info: StatementMetaWriter<'_>) -> io::Result<()> {
+            info.reply(1, &[], &columns())
+        }
+
+        async fn on_execute(
+            &mut self,
+            _: u32,
+            _: ParamParser<'_>,
+            w: QueryResultWriter<'_>,
+        ) -> io::Result<()> {
+            let rows = (0..5).map(|n| Ok(vec![myc::value::Value::Int(n)]));
+            let cols = columns();
+            w.open_cursor(&cols, Box::pin(futures::stream::iter(rows)))
+                .await
+        }
+    }
+
+    fn columns() -> [Column; 1] {
+        [Column {
+            table: String::new(),
+            column: "n".to_owned(),
+            coltype: myc::constants::ColumnType::MYSQL_TYPE_LONGLONG,
+            colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
+        }]
+    }
+
+    fn eof_status(eof: &[u8]) -> StatusFlags {
+        assert_eq!(eof[0], 0xFE, "expected EOF, got {:?}", eof);
+        StatusFlags::from_bits_truncate(u16::from_le_bytes([eof[3], eof[4]]))
+    }
+
+    async fn read_rows(c: &mut Client) -> (Vec<i64>, StatusFlags) {
+        let mut rows = Vec::new();
+        loop {
+            let row = c.read_packet().await;
+            if row[0] == 0xFE {
+                return (rows, eof_status(&row));
+            }
+            let mut n = [0; 8];
+            n.copy_from_slice(&row[2..]);
+            rows.push(i64::from_le_bytes(n));
+        }
+    }
+
+    duplex_test(TestingShim, |mut c| async move {
+        c.handshake("jon").await;
+        c.command(b"\x16SELECT n").await; // COM_STMT_PREPARE
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.read_packet().await; // column definition
+        c.read_packet().await; // EOF
+
+        // COM_STMT_EXECUTE with CURSOR_TYPE_READ_ONLY
+        c.command(b"\x17\x01\x00\x00\x00\x01\x01\x00\x00\x00").await;
+        assert_eq!(c.read_packet().await, vec![1]);
+        c.read_packet().await; // column definition
+        let status = eof_status(&c.read_packet().await);
+        assert!(status.contains(StatusFlags::SERVER_STATUS_CURSOR_EXISTS));
+
+        c.command(b"\x1c\x01\x00\x00\x00\x02\x00\x00\x00").await; // COM_STMT_FETCH
+        let (rows, status) = read_rows(&mut c).await;
+        assert_eq!(rows, vec![0, 1]);
+        assert!(status.contains(StatusFlags::SERVER_STATUS_CURSOR_EXISTS));
+        assert!(!status.contains(StatusFlags::SERVER_STATUS_LAST_ROW_SENT));
+
+        c.command(b"\x1c\x01\x00\x00\x00\x0a\x00\x00\x00").await;
+        let (rows, status) = read_rows(&mut c).await;
+        assert_eq!(rows, vec![2, 3, 4]);
+        assert!(status.contains(StatusFlags::SERVER_STATUS_LAST_ROW_SENT));
+
+        c.command(b"\x1c\x01\x00\x00\x00\x0a\x00\x00\x00").await;
+        let err = c.read_packet().await;
+        assert_eq!(err[0], 0xFF);
+        assert_eq!(
+            u16::from_le_bytes([err[1], err[2]]),
+            ErrorKind::ER_STMT_HAS_NO_OPEN_CURSOR as u16
+        );
+
+        // without a cursor, all the rows are sent right away
+        c.command(b"\x17\x01\x00\x00\x00\x00\x01\x00\x00\x00").await;
+        assert_eq!(c.read_packet().await, vec![1]);
+        c.read_packet().await; // column definition
+        let status = eof_status(&c.read_packet().await);
+        assert!(!status.contains(StatusFlags::SERVER_STATUS_CURSOR_EXISTS));
+        let (rows, _) = read_rows(&mut c).await;
+        assert_eq!(rows, vec![0, 1, 2, 3, 4]);
+
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_deprecates_eof() {
+    use std::iter;
+
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_prepare(&mut self, _: &str, info: StatementMetaWriter<'_>) -> io::Result<()> {
+            info.reply(1, &columns(), &columns())
+        }
+
+        async fn on_execute(
+            &mut self,
+            _: u32,
+            _: ParamParser<'_>,
+            w: QueryResultWriter<'_>,
+        ) -> io::Result<()> {
+            let cols = columns();
+            let mut w = w.start(&cols).await?;
+            w.write_row(iter::once(7i64))?;
+            w.finish().await
+        }
+
+        async fn on_query(&mut self, _: &str, w: QueryResultWriter<'_>) -> io::Result<()> {
+            let cols = columns();
+            let mut w = w.start(&cols).await?;
+            w.write_row(iter::once(7i64))?;
+            w.finish().await
+        }
+    }
+
+    fn columns() -> [Column; 1] {
+        [Column {
+            table: String::new(),
+            column: "n".to_owned(),
+            coltype: myc::constants::ColumnType::MYSQL_TYPE_LONGLONG,
+            colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
+        }]
+    }
+
+    fn assert_ok_eof(ok: &[u8]) {
+        // an OK packet with the EOF header, no affected rows, no last insert id, no status flags,
+        // and no warnings
+        assert_eq!(ok, &[0xFE, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    }
+
+    duplex_test(TestingShim, |mut c| async move {
+        c.read_greeting().await;
+        let mut response = Vec::new();
+        // CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_DEPRECATE_EOF
+        response.extend(&0x0100_8200u32.to_le_bytes());
+        response.extend(&16_777_216u32.to_le_bytes());
+        response.push(0x21);
+        response.extend(&[0; 23]);
+        response.extend(b"jon\0\0");
+        c.write_packet(&response).await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+
+        c.command(b"\x03SELECT n").await;
+        assert_eq!(c.read_packet().await, vec![1]);
+        assert_eq!(&c.read_packet().await[..4], b"\x03def"); // column definition
+        assert_eq!(c.read_packet().await, b"\x017");
+        assert_ok_eof(&c.read_packet().await);
+
+        c.command(b"\x16SELECT n").await; // COM_STMT_PREPARE
+        assert_eq!(c.read_packet().await[0], 0x00);
+        assert_eq!(&c.read_packet().await[..4], b"\x03def"); // parameter definition
+        assert_eq!(&c.read_packet().await[..4], b"\x03def"); // column definition
+
+        // COM_STMT_EXECUTE, with the parameter's type and value
+        c.command(b"\x17\x01\x00\x00\x00\x00\x01\x00\x00\x00\x00\x01\x08\x00\x07\x00\x00\x00\x00\x00\x00\x00")
+            .await;
+        assert_eq!(c.read_packet().await, vec![1]);
+        assert_eq!(&c.read_packet().await[..4], b"\x03def"); // column definition
+        assert_eq!(
+            c.read_packet().await,
+            vec![0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_ok_eof(&c.read_packet().await);
+
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_tracks_session_state() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_init(&mut self, _: &str, w: InitWriter<'_>) -> io::Result<()> {
+            w.ok()
+        }
+
+        async fn on_query(&mut self, _: &str, mut w: QueryResultWriter<'_>) -> io::Result<()> {
+            w.session_state(&SessionStateChange::SystemVariable {
+                name: "autocommit".to_owned(),
+                value: "OFF".to_owned(),
+            })?;
+            w.completed(0, 0).await
+        }
+    }
+
+    async fn log_in(c: &mut Client, capabilities: u32) {
+        c.read_greeting().await;
+        let mut response = Vec::new();
+        response.extend(&capabilities.to_le_bytes());
+        response.extend(&16_777_216u32.to_le_bytes());
+        response.push(0x21);
+        response.extend(&[0; 23]);
+        response.extend(b"jon\0\0");
+        c.write_packet(&response).await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+    }
+
+    // CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_SESSION_TRACK
+    duplex_test(TestingShim, |mut c| async move {
+        log_in(&mut c, 0x0080_8200).await;
+
+        c.command(b"\x02db").await; // COM_INIT_DB
+        assert_eq!(
+            c.read_packet().await,
+            b"\x00\x00\x00\x00\x40\x00\x00\x00\x05\x01\x03\x02db"
+        );
+
+        c.command(b"\x03SET autocommit = 0").await;
+        assert_eq!(
+            c.read_packet().await,
+            b"\x00\x00\x00\x00\x40\x00\x00\x00\x11\x00\x0f\x0aautocommit\x03OFF"
+        );
+
+        // the changes are only reported once
+        c.command(&[0x0e]).await; // COM_PING
+        assert_eq!(c.read_packet().await, b"\x00\x00\x00\x00\x00\x00\x00");
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+
+    // CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION
+    duplex_test(TestingShim, |mut c| async move {
+        log_in(&mut c, 0x0000_8200).await;
+        c.command(b"\x02db").await; // COM_INIT_DB
+        assert_eq!(c.read_packet().await, b"\x00\x00\x00\x00\x00\x00\x00");
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_reports_warnings() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_query(&mut self, q: &str, mut w: QueryResultWriter<'_>) -> io::Result<()> {
+            if q == "FAIL" {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "failed"));
+            }
+            w.warning(Warning {
+                level: WarningLevel::Warning,
+                code: ErrorKind::WARN_DATA_TRUNCATED,
+                message: "Data truncated for column 'a' at row 1".to_owned(),
+            });
+            w.info("Rows matched: 3  Changed: 2  Warnings: 1");
+            w.completed(2, 0).await
+        }
+
+        async fn on_prepare(&mut self, q: &str, mut w: StatementMetaWriter<'_>) -> io::Result<()> {
+            if q == "FAIL" {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "prepare failed"));
+            }
+            w.warning(Warning {
+                level: WarningLevel::Note,
+                code: ErrorKind::ER_UNKNOWN_ERROR,
+                message: "prepared".to_owned(),
+            });
+            w.reply(1, &[], &[])
+        }
+
+        async fn on_init(&mut self, _: &str, _: InitWriter<'_>) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "init failed"))
+        }
+
+        async fn on_list_fields(
+            &mut self,
+            _: &str,
+            _: &str,
+            _: ListFieldsWriter<'_>,
+        ) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "list failed"))
+        }
+    }
+
+    /// Read a text resultset, and return its rows with each column as a string.
+    async fn read_rows(c: &mut Client) -> Vec<Vec<String>> {
+        let columns = c.read_packet().await[0];
+        for _ in 0..columns {
+            c.read_packet().await;
+        }
+        assert_eq!(c.read_packet().await[0], 0xFE);
+        let mut rows = Vec::new();
+        loop {
+            let mut row = &c.read_packet().await[..];
+            if row[0] == 0xFE {
+                return rows;
+            }
+            let mut cols = Vec::new();
+            while let Some((&len, rest)) = row.split_first() {
+                let (col, rest) = rest.split_at(len as usize);
+                cols.push(String::from_utf8(col.to_vec()).unwrap());
+                row = rest;
+            }
+            rows.push(cols);
+        }
+    }
+
+    duplex_test(TestingShim, |mut c| async move {
+        c.handshake("jon").await;
+        c.command(b"\x03UPDATE t SET a = 'abc'").await;
+        let ok = c.read_packet().await;
+        assert_eq!(&ok[..7], &[0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00]);
+        assert_eq!(&ok[7..], b"Rows matched: 3  Changed: 2  Warnings: 1");
+
+        let warning = vec![
+            "Warning".to_owned(),
+            "1265".to_owned(),
+            "Data truncated for column 'a' at row 1".to_owned(),
+        ];
+        c.command(b"\x03SHOW WARNINGS").await;
+        assert_eq!(read_rows(&mut c).await, vec![warning.clone()]);
+        c.command(b"\x03SHOW COUNT(*) WARNINGS").await;
+        assert_eq!(read_rows(&mut c).await, vec![vec!["1".to_owned()]]);
+        c.command(b"\x03SELECT @@warning_count").await;
+        assert_eq!(read_rows(&mut c).await, vec![vec!["1".to_owned()]]);
+
+        // errors are listed as well
+        c.command(b"\x03FAIL").await;
+        assert_eq!(c.read_packet().await[0], 0xFF);
+        c.command(b"\x03SHOW WARNINGS").await;
+        assert_eq!(
+            read_rows(&mut c).await,
+            vec![vec![
+                "Error".to_owned(),
+                "1105".to_owned(),
+                "failed".to_owned()
+            ]]
+        );
+
+        // so are those of the commands that are not queries
+        c.command(b"\x16SELECT 1").await; // COM_STMT_PREPARE
+        let ok = c.read_packet().await;
+        assert_eq!(ok[0], 0x00);
+        assert_eq!(&ok[10..12], &[0x01, 0x00]);
+        c.command(b"\x03SHOW WARNINGS").await;
+        assert_eq!(
+            read_rows(&mut c).await,
+            vec![vec![
+                "Note".to_owned(),
+                "1105".to_owned(),
+                "prepared".to_owned()
+            ]]
+        );
+        for (command, message) in [
+            (&b"\x16FAIL"[..], "prepare failed"),
+            (&b"\x02db"[..], "init failed"),
+            (&b"\x04t\x00"[..], "list failed"),
+        ] {
+            c.command(command).await;
+            assert_eq!(c.read_packet().await[0], 0xFF);
+            c.command(b"\x03SHOW WARNINGS").await;
+            assert_eq!(
+                read_rows(&mut c).await,
+                vec![vec![
+                    "Error".to_owned(),
+                    "1105".to_owned(),
+                    message.to_owned()
+                ]]
+            );
+        }
+
+        c.command(&[0x1f]).await; // COM_RESET_CONNECTION
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(b"\x03SELECT @@warning_count").await;
+        assert_eq!(read_rows(&mut c).await, vec![vec!["0".to_owned()]]);
+
+        c.command(b"\x03UPDATE t SET a = 'abc'").await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(b"\x11jon\x00\x00\x00\x21\x00").await; // COM_CHANGE_USER
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(b"\x03SELECT @@warning_count").await;
+        assert_eq!(read_rows(&mut c).await, vec![vec!["0".to_owned()]]);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_keeps_status_flags_across_commands() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+
+        async fn on_query(&mut self, q: &str, mut w: QueryResultWriter<'_>) -> io::Result<()> {
+            match q {
+                "ROLLBACK" => Err(io::Error::new(io::ErrorKind::InvalidData, "failed")),
+                "SELECT * FROM t" => {
+                    w.set_status_flags(StatusFlags::SERVER_STATUS_NO_INDEX_USED, true);
+                    w.completed(0, 0).await
+                }
+                _ => w.completed(0, 0).await,
+            }
+        }
+    }
+
+    fn ok_status(ok: &[u8]) -> StatusFlags {
+        assert_eq!(ok[0], 0x00, "expected OK, got {:?}", ok);
+        StatusFlags::from_bits_truncate(u16::from_le_bytes([ok[3], ok[4]]))
+    }
+
+    let autocommit = StatusFlags::SERVER_STATUS_AUTOCOMMIT;
+    let in_trans = StatusFlags::SERVER_STATUS_IN_TRANS;
+    // connections start in autocommit mode, whichever order the options are set in
+    let options = ServerOptions::default()
+        .with_transaction_tracking(true)
+        .with_status_flags(StatusFlags::empty());
+    duplex_test_with_options(TestingShim, options, |mut c| async move {
+        let greeting = c.read_packet().await;
+        let version_end = greeting.iter().position(|&b| b == 0).unwrap();
+        let status = &greeting[version_end + 1 + 4 + 8 + 1 + 2 + 1..][..2];
+        assert_eq!(
+            u16::from_le_bytes([status[0], status[1]]),
+            autocommit.bits()
+        );
+        c.handshake_response("jon", &[]).await;
+        assert_eq!(ok_status(&c.read_packet().await), autocommit);
+
+        c.command(b"\x03BEGIN").await;
+        assert_eq!(ok_status(&c.read_packet().await), autocommit | in_trans);
+        c.command(&[0x0e]).await; // COM_PING
+        assert_eq!(ok_status(&c.read_packet().await), autocommit | in_trans);
+
+        // a statement that fails leaves the status as it was
+        c.command(b"\x03ROLLBACK").await;
+        assert_eq!(c.read_packet().await[0], 0xFF);
+        c.command(&[0x0e]).await; // COM_PING
+        assert_eq!(ok_status(&c.read_packet().await), autocommit | in_trans);
+
+        c.command(b"\x03COMMIT").await;
+        assert_eq!(ok_status(&c.read_packet().await), autocommit);
+        c.command(b"\x03SET autocommit = 0").await;
+        assert_eq!(ok_status(&c.read_packet().await), StatusFlags::empty());
+
+        c.command(b"\x03SELECT * FROM t").await;
+        let no_index = StatusFlags::SERVER_STATUS_NO_INDEX_USED;
+        assert_eq!(ok_status(&c.read_packet().await), no_index);
+        c.command(&[0x0e]).await; // COM_PING
+        assert_eq!(ok_status(&c.read_packet().await), no_index);
+
+        c.command(&[0x1f]).await; // COM_RESET_CONNECTION
+        assert_eq!(ok_status(&c.read_packet().await), autocommit);
+
+        c.command(b"\x03SET autocommit = 0").await;
+        assert_eq!(ok_status(&c.read_packet().await), StatusFlags::empty());
+        c.command(b"\x11jon\x00\x00\x00\x21\x00").await; // COM_CHANGE_USER
+        assert_eq!(ok_status(&c.read_packet().await), autocommit);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+fn caching_sha2_password_options() -> ServerOptions {
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+    native_password_options()
+        .with_auth_plugin(AuthPlugin::CachingSha2Password)
+        .with_rsa_key(key)
+}
+
+/// Encrypt `password` the way clients do for `caching_sha2_password` full authentication.
+fn encrypt_password(pem: &[u8], scramble: &[u8], password: &[u8]) -> Vec<u8> {
+    let key = RsaPublicKey::from_public_key_pem(std::str::from_utf8(pem).unwrap()).unwrap();
+    let mut data = password.to_vec();
+    data.push(0);
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= scramble[i % scramble.len()];
+    }
+    key.encrypt(&mut rand::thread_rng(), Oaep::new::<sha1::Sha1>(), &data)
+        .unwrap()
+}
+
+#[test]
+fn it_accepts_caching_sha2_password() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    let options = caching_sha2_password_options();
+
+    // the first login needs the full password, which we send encrypted with the server's key
+    duplex_test_with_options(TestingShim, options.clone(), |mut c| async move {
+        let scramble = c.read_greeting().await;
+        let token = myc::scramble::scramble_sha256(&scramble, b"hunter2").unwrap();
+        c.handshake_response_with_plugin("jon", &token, "caching_sha2_password")
+            .await;
+        assert_eq!(c.read_packet().await, vec![0x01, 0x04]); // perform full authentication
+
+        c.write_packet(&[0x02]).await; // request public key
+        let pem = c.read_packet().await;
+        assert_eq!(pem[0], 0x01);
+        c.write_packet(&encrypt_password(&pem[1..], &scramble, b"hunter2"))
+            .await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+
+    // after which the scramble alone is enough
+    duplex_test_with_options(TestingShim, options, |mut c| async move {
+        let scramble = c.read_greeting().await;
+        let token = myc::scramble::scramble_sha256(&scramble, b"hunter2").unwrap();
+        c.handshake_response_with_plugin("jon", &token, "caching_sha2_password")
+            .await;
+        assert_eq!(c.read_packet().await, vec![0x01, 0x03]); // fast auth success
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_denies_wrong_caching_sha2_password() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    let options = caching_sha2_password_options();
+    duplex_test_with_options(TestingShim, options.clone(), |mut c| async move {
+        let scramble = c.read_greeting().await;
+        let token = myc::scramble::scramble_sha256(&scramble, b"hunter3").unwrap();
+        c.handshake_response_with_plugin("jon", &token, "caching_sha2_password")
+            .await;
+        assert_eq!(c.read_packet().await, vec![0x01, 0x04]);
+
+        c.write_packet(&[0x02]).await;
+        let pem = c.read_packet().await;
+        c.write_packet(&encrypt_password(&pem[1..], &scramble, b"hunter3"))
+            .await;
+        let err = c.read_packet().await;
+        assert_eq!(err[0], 0xFF);
+        assert_eq!(
+            u16::from_le_bytes([err[1], err[2]]),
+            ErrorKind::ER_ACCESS_DENIED_ERROR as u16
+        );
+    });
+
+    // a failed login must not make it into the cache
+    duplex_test_with_options(TestingShim, options, |mut c| async move {
+        let scramble = c.read_greeting().await;
+        let token = myc::scramble::scramble_sha256(&scramble, b"hunter3").unwrap();
+        c.handshake_response_with_plugin("jon", &token, "caching_sha2_password")
+            .await;
+        assert_eq!(c.read_packet().await, vec![0x01, 0x04]);
+
+        c.write_packet(&[0x02]).await;
+        let pem = c.read_packet().await;
+        c.write_packet(&encrypt_password(&pem[1..], &scramble, b"hunter3"))
+            .await;
+        assert_eq!(c.read_packet().await[0], 0xFF);
+    });
+}
+
+#[test]
+fn it_switches_to_the_default_plugin() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    duplex_test_with_options(TestingShim, native_password_options(), |mut c| async move {
+        let greeting = c.read_packet().await;
+        assert!(greeting.ends_with(b"mysql_native_password\0"));
+
+        // a MySQL 8 client answers with its own default plugin
+        c.handshake_response_with_plugin("jon", &[0; 32], "caching_sha2_password")
+            .await;
+        let (plugin, scramble) = c.read_auth_switch_request().await;
+        assert_eq!(plugin, "mysql_native_password");
+        let token = myc::scramble::scramble_native(&scramble, b"hunter2").unwrap();
+        c.write_packet(&token).await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[test]
+fn it_switches_plugins_per_user() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    // lets "ldap" in with a password only it can check, and everyone else with native passwords
+    struct LdapAuthenticator(NativePasswordAuthenticator);
+    #[async_trait]
+    impl Authenticator for LdapAuthenticator {
+        async fn authenticate(&self, username: &str, scramble: &[u8], response: &[u8]) -> bool {
+            self.0.authenticate(username, scramble, response).await
+        }
+
+        async fn verify_password(&self, username: &str, password: &[u8]) -> bool {
+            username == "ldap" && password == b"s3cret"
+        }
+
+        async fn auth_plugin(&self, username: &str) -> Option<AuthPlugin> {
+            if username == "ldap" {
+                Some(AuthPlugin::MysqlClearPassword)
+            } else {
+                None
+            }
+        }
+    }
+
+    let options = || {
+        let mut auth = NativePasswordAuthenticator::new();
+        auth.add_user("jon", b"hunter2");
+        ServerOptions::default().with_authenticator(LdapAuthenticator(auth))
+    };
+
+    for &(password, ok) in &[(&b"s3cret"[..], true), (b"hunter2", false)] {
+        duplex_test_with_options(TestingShim, options(), |mut c| async move {
+            let scramble = c.read_greeting().await;
+            let token = myc::scramble::scramble_native(&scramble, password).unwrap();
+            c.handshake_response_with_plugin("ldap", &token, "mysql_native_password")
+                .await;
+            let (plugin, _) = c.read_auth_switch_request().await;
+            assert_eq!(plugin, "mysql_clear_password");
+            let mut cleartext = password.to_vec();
+            cleartext.push(0);
+            c.write_packet(&cleartext).await;
+            let reply = c.read_packet().await;
+            if ok {
+                assert_eq!(reply[0], 0x00);
+                c.command(&[0x01]).await; // COM_QUIT
+            } else {
+                assert_eq!(reply[0], 0xFF);
+            }
+        });
+    }
+
+    // users without a plugin of their own are not asked to switch
+    duplex_test_with_options(TestingShim, options(), |mut c| async move {
+        let scramble = c.read_greeting().await;
+        let token = myc::scramble::scramble_native(&scramble, b"hunter2").unwrap();
+        c.handshake_response_with_plugin("jon", &token, "mysql_native_password")
+            .await;
+        assert_eq!(c.read_packet().await[0], 0x00);
+        c.command(&[0x01]).await; // COM_QUIT
+    });
+}
+
+#[cfg(feature = "ed25519")]
+#[test]
+fn it_accepts_client_ed25519() {
+    use ed25519_dalek::hazmat::{raw_sign, ExpandedSecretKey};
+    use sha2::{Digest, Sha512};
+
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    struct Ed25519Authenticator;
+    #[async_trait]
+    impl Authenticator for Ed25519Authenticator {
+        async fn auth_plugin(&self, _: &str) -> Option<AuthPlugin> {
+            Some(AuthPlugin::ClientEd25519)
+        }
+
+        async fn ed25519_public_key(&self, username: &str) -> Option<[u8; 32]> {
+            if username == "jon" {
+                Some(msql_srv::ed25519_password(b"hunter2"))
+            } else {
+                None
+            }
+        }
+    }
+
+    // what MariaDB Connector/C does with the challenge
+    fn sign(password: &[u8], nonce: &[u8]) -> Vec<u8> {
+        let expanded = ExpandedSecretKey::from_bytes(&Sha512::digest(password).into());
+        let public_key = (&expanded).into();
+        raw_sign::<Sha512>(&expanded, nonce, &public_key)
+            .to_bytes()
+            .to_vec()
+    }
+
+    for &(user, password, ok) in &[
+        ("jon", &b"hunter2"[..], true),
+        ("jon", b"hunter3", false),
+        ("nobody", b"hunter2", false),
+    ] {
+        let options = ServerOptions::default().with_authenticator(Ed25519Authenticator);
+        duplex_test_with_options(TestingShim, options, |mut c| async move {
+            let scramble = c.read_greeting().await;
+            let token = myc::scramble::scramble_native(&scramble, password).unwrap();
+            c.handshake_response_with_plugin(user, &token, "mysql_native_password")
+                .await;
+
+            let packet = c.read_packet().await;
+            assert_eq!(&packet[..16], b"\xFEclient_ed25519\0");
+            let nonce = &packet[16..];
+            assert_eq!(nonce.len(), 32);
+            assert_eq!(&nonce[..20], &scramble[..]);
+            c.write_packet(&sign(password, nonce)).await;
+
+            let reply = c.read_packet().await;
+            if ok {
+                assert_eq!(reply[0], 0x00);
+                c.command(&[0x01]).await; // COM_QUIT
+            } else {
+                assert_eq!(reply[0], 0xFF);
+            }
+        });
+    }
+}
+
+#[test]
+fn it_uses_a_new_scramble_for_each_connection() {
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    let mut scrambles = Vec::new();
+    for _ in 0..2 {
+        let scrambles = &mut scrambles;
+        duplex_test(TestingShim, |mut c| async move {
+            let scramble = c.read_greeting().await;
+            assert_eq!(scramble.len(), 20);
+            assert!(!scramble.contains(&0));
+            scrambles.push(scramble);
+            c.handshake_response("jon", &[]).await;
+            assert_eq!(c.read_packet().await[0], 0x00);
+            c.command(&[0x01]).await; // COM_QUIT
+        });
+    }
+    assert_ne!(scrambles[0], scrambles[1]);
+}
+
+#[cfg(feature = "tls")]
+mod tls {
+    use super::*;
+    use msql_srv::rustls;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    struct TestingShim;
+    #[async_trait]
+    impl MysqlShim for TestingShim {
+        type Error = io::Error;
+    }
+
+    fn tls_configs() -> (Arc<rustls::ServerConfig>, Arc<rustls::ClientConfig>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key_der = rustls::PrivateKey(cert.serialize_private_key_der());
+
+        let server = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der)
+            .unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&cert_der).unwrap();
+        let client = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        (Arc::new(server), Arc::new(client))
+    }
+
+    #[test]
+    fn it_upgrades_to_tls() {
+        let (server, client) = tls_configs();
+        let options = ServerOptions::default()
+            .with_tls(server)
+            .require_secure_transport(true);
+
+        duplex_test_with_options(TestingShim, options, |mut c| async move {
+            let greeting = c.read_packet().await;
+            let version_end = greeting.iter().position(|&b| b == 0).unwrap();
+            let caps = &greeting[version_end + 1 + 4 + 9..];
+            // CLIENT_SSL
+            assert_ne!(u16::from_le_bytes([caps[0], caps[1]]) & 0x0800, 0);
+
+            // SSLRequest: CLIENT_PROTOCOL_41 | CLIENT_SSL | CLIENT_SECURE_CONNECTION
+            let mut request = Vec::new();
+            request.extend(&0x0000_8a00u32.to_le_bytes());
+            request.extend(&16_777_216u32.to_le_bytes());
+            request.push(0x21);
+            request.extend(&[0; 23]);
+            c.write_packet(&request).await;
+
+            let stream = tokio_rustls::TlsConnector::from(client)
+                .connect(rustls::ServerName::try_from("localhost").unwrap(), c.stream)
+                .await
+                .unwrap();
+            let mut c = Client { stream, seq: c.seq };
+
+            c.handshake_response("jon", &[]).await;
+            assert_eq!(c.read_packet().await[0], 0x00);
+
+            c.command(&[0x0e]).await; // COM_PING
+            assert_eq!(c.read_packet().await[0], 0x00);
+            c.command(&[0x01]).await; // COM_QUIT
+        });
+    }
+
+    #[test]
+    fn it_accepts_cleartext_caching_sha2_password_over_tls() {
+        let (server, client) = tls_configs();
+        let mut auth = NativePasswordAuthenticator::new();
+        auth.add_user("jon", b"hunter2");
+        let options = ServerOptions::default()
+            .with_authenticator(auth)
+            .with_auth_plugin(AuthPlugin::CachingSha2Password)
+            .with_tls(server);
+
+        duplex_test_with_options(TestingShim, options, |mut c| async move {
+            let scramble = c.read_greeting().await;
+
+            let mut request = Vec::new();
+            request.extend(&0x0000_8a00u32.to_le_bytes());
+            request.extend(&16_777_216u32.to_le_bytes());
+            request.push(0x21);
+            request.extend(&[0; 23]);
+            c.write_packet(&request).await;
+
+            let stream = tokio_rustls::TlsConnector::from(client)
+                .connect(rustls::ServerName::try_from("localhost").unwrap(), c.stream)
+                .await
+                .unwrap();
+            let mut c = Client { stream, seq: c.seq };
+
+            let token = myc::scramble::scramble_sha256(&scramble, b"hunter2").unwrap();
+            c.handshake_response_with_plugin("jon", &token, "caching_sha2_password")
+                .await;
+            assert_eq!(c.read_packet().await, vec![0x01, 0x04]);
+            c.write_packet(b"hunter2\0").await;
+            assert_eq!(c.read_packet().await[0], 0x00);
+            c.command(&[0x01]).await; // COM_QUIT
+        });
+    }
+
+    #[test]
+    fn it_requires_secure_transport() {
+        let (server, _) = tls_configs();
+        let options = ServerOptions::default()
+            .with_tls(server)
+            .require_secure_transport(true);
+
+        duplex_test_with_options(TestingShim, options, |mut c| async move {
+            c.read_greeting().await;
+            c.handshake_response("jon", &[]).await;
+            let err = c.read_packet().await;
+            assert_eq!(err[0], 0xFF);
+            assert_eq!(
+                u16::from_le_bytes([err[1], err[2]]),
+                ErrorKind::ER_SECURE_TRANSPORT_REQUIRED as u16
+            );
+        });
+    }
+}
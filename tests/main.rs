@@ -167,6 +167,7 @@ fn no_rows() {
                 column: "a".to_owned(),
                 coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
                 colflags: myc::constants::ColumnFlags::empty(),
+                ..Default::default()
             }],
         },
         |db| {
@@ -261,6 +262,7 @@ fn empty_on_drop() {
                 column: "a".to_owned(),
                 coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
                 colflags: myc::constants::ColumnFlags::empty(),
+                ..Default::default()
             }],
         },
         |db| {
@@ -282,6 +284,7 @@ fn it_queries_nulls() {
                 column: "a".to_owned(),
                 coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
                 colflags: myc::constants::ColumnFlags::empty(),
+                ..Default::default()
             }];
             let mut w = w.start(cols).await?;
             w.write_col(None::<i16>)?;
@@ -312,6 +315,7 @@ fn it_queries() {
                 column: "a".to_owned(),
                 coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
                 colflags: myc::constants::ColumnFlags::empty(),
+                ..Default::default()
             }];
             let mut w = w.start(cols).await?;
             w.write_col(1024i16)?;
@@ -343,6 +347,7 @@ fn multi_result() {
                 column: "a".to_owned(),
                 coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
                 colflags: myc::constants::ColumnFlags::empty(),
+                ..Default::default()
             }];
             let mut row = w.start(cols).await?;
             row.write_col(1024i16)?;
@@ -389,12 +394,14 @@ fn it_queries_many_rows() {
                     column: "a".to_owned(),
                     coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
                     colflags: myc::constants::ColumnFlags::empty(),
+                    ..Default::default()
                 },
                 Column {
                     table: String::new(),
                     column: "b".to_owned(),
                     coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
                     colflags: myc::constants::ColumnFlags::empty(),
+                    ..Default::default()
                 },
             ];
             let mut w = w.start(cols).await?;
@@ -424,6 +431,7 @@ fn it_prepares() {
         column: "a".to_owned(),
         coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
         colflags: myc::constants::ColumnFlags::empty(),
+        ..Default::default()
     }];
     pub struct TestingShim {
         cols: Vec<Column>,
@@ -443,6 +451,7 @@ fn it_prepares() {
                 column: "c".to_owned(),
                 coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
                 colflags: myc::constants::ColumnFlags::empty(),
+                ..Default::default()
             }];
             info.reply(41, &params, &self.cols)
         }
@@ -549,42 +558,49 @@ fn insert_exec() {
             column: "username".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_VARCHAR,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
         Column {
             table: String::new(),
             column: "email".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_VARCHAR,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
         Column {
             table: String::new(),
             column: "pw".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_VARCHAR,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
         Column {
             table: String::new(),
             column: "created".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_DATETIME,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
         Column {
             table: String::new(),
             column: "session".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_VARCHAR,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
         Column {
             table: String::new(),
             column: "rss".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_VARCHAR,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
         Column {
             table: String::new(),
             column: "mail".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_VARCHAR,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
     ];
 
@@ -618,12 +634,14 @@ fn send_long() {
         column: "a".to_owned(),
         coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
         colflags: myc::constants::ColumnFlags::empty(),
+        ..Default::default()
     }];
     let params = vec![Column {
         table: String::new(),
         column: "c".to_owned(),
         coltype: myc::constants::ColumnType::MYSQL_TYPE_BLOB,
         colflags: myc::constants::ColumnFlags::empty(),
+        ..Default::default()
     }];
 
     pub struct TestingShim {
@@ -683,12 +701,14 @@ fn it_prepares_many() {
             column: "a".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
         Column {
             table: String::new(),
             column: "b".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
     ];
     pub struct TestingShim {
@@ -739,12 +759,14 @@ fn prepared_empty() {
         column: "a".to_owned(),
         coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
         colflags: myc::constants::ColumnFlags::empty(),
+        ..Default::default()
     }];
     let params = vec![Column {
         table: String::new(),
         column: "c".to_owned(),
         coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
         colflags: myc::constants::ColumnFlags::empty(),
+        ..Default::default()
     }];
     pub struct TestingShim {
         cols: Vec<Column>,
@@ -785,6 +807,7 @@ fn prepared_no_params() {
         column: "a".to_owned(),
         coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
         colflags: myc::constants::ColumnFlags::empty(),
+        ..Default::default()
     }];
     let params = vec![];
     pub struct TestingShim {
@@ -825,12 +848,14 @@ fn prepared_nulls() {
             column: "a".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
         Column {
             table: String::new(),
             column: "b".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
     ];
     let params = vec![
@@ -839,12 +864,14 @@ fn prepared_nulls() {
             column: "c".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
         Column {
             table: String::new(),
             column: "d".to_owned(),
             coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
             colflags: myc::constants::ColumnFlags::empty(),
+            ..Default::default()
         },
     ];
     pub struct TestingShim {
@@ -906,6 +933,7 @@ fn prepared_no_rows() {
         column: "a".to_owned(),
         coltype: myc::constants::ColumnType::MYSQL_TYPE_SHORT,
         colflags: myc::constants::ColumnFlags::empty(),
+        ..Default::default()
     }];
     let params = vec![];
     pub struct TestingShim {